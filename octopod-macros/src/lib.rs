@@ -1,16 +1,24 @@
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{parse_macro_input, Ident, ItemFn, LitStr, Token};
+use syn::{parse_macro_input, FnArg, Ident, ItemFn, LitStr, Pat, Token, Type};
 
 struct TestParams {
     app: LitStr,
     ignore: bool,
+    show_logs: bool,
+    ignore_if: Option<LitStr>,
+    name: Option<LitStr>,
+    env: Vec<(LitStr, LitStr)>,
 }
 
 impl syn::parse::Parse for TestParams {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
         let mut app = None;
         let mut ignore = false;
+        let mut show_logs = false;
+        let mut ignore_if = None;
+        let mut name = None;
+        let mut env = Vec::new();
         while !input.is_empty() {
             let key: Ident = input.parse()?;
             match key.to_string().as_str() {
@@ -21,6 +29,21 @@ impl syn::parse::Parse for TestParams {
                 "ignore" => {
                     ignore = true;
                 }
+                "show_logs" => {
+                    show_logs = true;
+                }
+                "ignore_if" if ignore_if.is_none() => {
+                    let _: Token!(=) = input.parse()?;
+                    ignore_if.replace(input.parse()?);
+                }
+                "name" if name.is_none() => {
+                    let _: Token!(=) = input.parse()?;
+                    name.replace(input.parse()?);
+                }
+                "env" => {
+                    let _: Token!(=) = input.parse()?;
+                    env.extend(parse_env(input)?);
+                }
                 other => {
                     return Err(syn::Error::new(
                         key.span(),
@@ -42,8 +65,63 @@ impl syn::parse::Parse for TestParams {
             )
         })?;
 
-        Ok(Self { app, ignore })
+        Ok(Self {
+            app,
+            ignore,
+            show_logs,
+            ignore_if,
+            name,
+            env,
+        })
+    }
+}
+
+/// Parses a list of environment variables, e.g `[("KEY", "value"), ...]`.
+fn parse_env(input: syn::parse::ParseStream) -> syn::Result<Vec<(LitStr, LitStr)>> {
+    let content;
+    syn::bracketed!(content in input);
+    let mut env = Vec::new();
+    while !content.is_empty() {
+        let pair;
+        syn::parenthesized!(pair in content);
+        let key: LitStr = pair.parse()?;
+        let _: Token!(,) = pair.parse()?;
+        let value: LitStr = pair.parse()?;
+        env.push((key, value));
+
+        if !content.is_empty() {
+            let _: Token!(,) = content.parse()?;
+        }
     }
+
+    Ok(env)
+}
+
+/// If the test takes `&Service` parameters, e.g `async fn t(db: &Service)`, returns the names of
+/// the parameters, that are the names of the services to inject. Returns `None` if the test
+/// should be passed the `App`.
+fn injected_services(fun: &ItemFn) -> Option<Vec<Ident>> {
+    let mut names = Vec::new();
+    for arg in &fun.sig.inputs {
+        let FnArg::Typed(arg) = arg else {
+            return None;
+        };
+        let Type::Reference(ty) = &*arg.ty else {
+            return None;
+        };
+        let Type::Path(path) = &*ty.elem else {
+            return None;
+        };
+        if path.path.segments.last()?.ident != "Service" {
+            return None;
+        }
+        let Pat::Ident(pat) = &*arg.pat else {
+            return None;
+        };
+        names.push(pat.ident.clone());
+    }
+
+    (!names.is_empty()).then_some(names)
 }
 
 #[proc_macro_attribute]
@@ -55,16 +133,54 @@ pub fn test(attr: TokenStream, input: TokenStream) -> TokenStream {
     let fun_name_str = fun_name.to_string();
     let app = &params.app;
     let ignore = params.ignore;
+    let show_logs = params.show_logs;
+    let ignore_if = match params.ignore_if {
+        Some(condition) => quote!(Some(#condition)),
+        None => quote!(None),
+    };
+    let (env_keys, env_values): (Vec<_>, Vec<_>) = params.env.into_iter().unzip();
+    let display_name = match params.name {
+        Some(name) => quote!(Some(#name)),
+        None => quote!(None),
+    };
+
+    // tests taking services are wrapped in a function taking the app, that looks the services up
+    // by name
+    let (f, wrapper) = match injected_services(&fun) {
+        Some(services) => {
+            let wrapper_name = quote::format_ident!("__octopod_{}", fun_name);
+            let service_names = services.iter().map(|s| s.to_string());
+            let wrapper = quote! {
+                #[doc(hidden)]
+                async fn #wrapper_name(app: octopod::App) {
+                    #(
+                        let #services = app
+                            .service_expect(#service_names)
+                            .unwrap_or_else(|e| panic!("{e:#}"));
+                    )*
+                    #fun_name(#(#services),*).await
+                }
+            };
+            (quote!(#wrapper_name), wrapper)
+        }
+        None => (quote!(#fun_name), quote!()),
+    };
 
     quote! {
         octopod::sealed::inventory::submit!(
             octopod::sealed::TestDecl {
                 name: concat!(module_path!(), "::", #fun_name_str),
-                f: &#fun_name,
+                display_name: #display_name,
+                env: &[#((#env_keys, #env_values)),*],
+                f: &#f,
                 app: #app,
                 ignore: #ignore,
+                show_logs: #show_logs,
+                ignore_if: #ignore_if,
             });
 
+        #wrapper
+
         #fun
     }
     .into()
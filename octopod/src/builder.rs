@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+
+use crate::{AppConfig, Octopod, OctopodError, Reporter};
+
+/// Builder for `Octopod`, returned by `Octopod::builder`. The tests are collected when the
+/// builder is built. Options that are not exposed by the builder can be set on the built
+/// `Octopod`.
+#[derive(Default)]
+pub struct OctopodBuilder {
+    podman_addr: Option<String>,
+    apps: Vec<AppConfig>,
+    lazy_apps: Vec<Box<dyn FnOnce() -> Vec<AppConfig>>>,
+    reporters: Vec<Box<dyn Reporter>>,
+    max_concurrency: Option<usize>,
+    timeout: Option<Duration>,
+    filters: Vec<String>,
+    rebuild_images: bool,
+}
+
+impl OctopodBuilder {
+    /// Set the address of the podman API.
+    pub fn podman_addr(mut self, addr: impl Into<String>) -> Self {
+        self.podman_addr = Some(addr.into());
+        self
+    }
+
+    /// Register an app.
+    pub fn app(mut self, app: AppConfig) -> Self {
+        self.apps.push(app);
+        self
+    }
+
+    /// Register apps that are only defined when the builder is built, e.g because they depend on
+    /// information discovered at startup.
+    pub fn apps_with(mut self, f: impl FnOnce() -> Vec<AppConfig> + 'static) -> Self {
+        self.lazy_apps.push(Box::new(f));
+        self
+    }
+
+    /// See `Octopod::reporter`.
+    pub fn reporter(mut self, reporter: impl Reporter + 'static) -> Self {
+        self.reporters.push(Box::new(reporter));
+        self
+    }
+
+    /// See `Octopod::max_concurrency`.
+    pub fn max_concurrency(mut self, n: usize) -> Self {
+        self.max_concurrency = Some(n);
+        self
+    }
+
+    /// See `Octopod::timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Apply the libtest options from the command line arguments, e.g `std::env::args().skip(1)`.
+    /// `--test-threads N` sets `max_concurrency`, and `--rebuild` sets `Octopod::rebuild_images`.
+    /// Other arguments are ignored.
+    pub fn args(mut self, args: impl IntoIterator<Item = String>) -> Result<Self, OctopodError> {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--rebuild" {
+                self.rebuild_images = true;
+                continue;
+            }
+            let threads = match arg.strip_prefix("--test-threads") {
+                Some("") => args
+                    .next()
+                    .ok_or_else(|| OctopodError::config("missing value for `--test-threads`"))?,
+                Some(value) => match value.strip_prefix('=') {
+                    Some(value) => value.to_string(),
+                    None => continue,
+                },
+                None => continue,
+            };
+            self.max_concurrency =
+                Some(parse_test_threads(&threads).map_err(OctopodError::Config)?);
+        }
+
+        Ok(self)
+    }
+
+    /// Only run the tests whose fully qualified name, or display name, contains `filter`. When
+    /// several filters are set, tests matching any of them are run.
+    pub fn filter(mut self, filter: impl Into<String>) -> Self {
+        self.filters.push(filter.into());
+        self
+    }
+
+    /// Connect to podman and collect the tests. Fails if no podman address was set.
+    pub fn build(self) -> Result<Octopod, OctopodError> {
+        let addr = self
+            .podman_addr
+            .clone()
+            .ok_or_else(|| OctopodError::config("no podman address set on the octopod builder"))?;
+        self.build_with_addr(&addr)
+    }
+
+    /// Like `build`, but reads the podman address from `OCTOPOD_PODMAN_ADDR`, or podman's own
+    /// `CONTAINER_HOST`, if it isn't set on the builder. Like libtest, the concurrency is read
+    /// from `RUST_TEST_THREADS` if it isn't set.
+    pub fn build_from_env(mut self) -> Result<Octopod, OctopodError> {
+        if self.max_concurrency.is_none() {
+            if let Ok(threads) = std::env::var("RUST_TEST_THREADS") {
+                let threads = parse_test_threads(&threads)
+                    .context("invalid RUST_TEST_THREADS")
+                    .map_err(OctopodError::Config)?;
+                self.max_concurrency = Some(threads);
+            }
+        }
+        let addr = match self.podman_addr {
+            Some(ref addr) => addr.clone(),
+            None => std::env::var("OCTOPOD_PODMAN_ADDR")
+                .or_else(|_| std::env::var("CONTAINER_HOST"))
+                .map_err(|_| {
+                    OctopodError::config("neither OCTOPOD_PODMAN_ADDR nor CONTAINER_HOST is set")
+                })?,
+        };
+        self.build_with_addr(&addr)
+    }
+
+    fn build_with_addr(self, addr: &str) -> Result<Octopod, OctopodError> {
+        let mut apps = self.apps;
+        for f in self.lazy_apps {
+            apps.extend(f());
+        }
+
+        let mut octopod = Octopod::with_filters(addr, apps, &self.filters)?;
+        octopod.reporters.extend(self.reporters);
+        if let Some(n) = self.max_concurrency {
+            octopod = octopod.max_concurrency(n);
+        }
+        if let Some(timeout) = self.timeout {
+            octopod = octopod.timeout(timeout);
+        }
+        if self.rebuild_images {
+            octopod = octopod.rebuild_images();
+        }
+
+        Ok(octopod)
+    }
+}
+
+fn parse_test_threads(threads: &str) -> anyhow::Result<usize> {
+    match threads.parse() {
+        Ok(0) | Err(_) => {
+            bail!("the number of test threads must be a positive integer, got `{threads}`")
+        }
+        Ok(n) => Ok(n),
+    }
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::{task::JoinHandle, time::Instant};
+
+use crate::{App, Service};
+
+/// A fault, or the recovery from a fault, applied to a service by a chaos schedule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    Pause,
+    Unpause,
+    /// Disconnect the service from the app network.
+    Disconnect,
+    /// Connect the service back to the app network.
+    Connect,
+    Restart,
+}
+
+impl Fault {
+    async fn apply(self, service: &Service) -> anyhow::Result<()> {
+        match self {
+            Fault::Pause => service.pause().await,
+            Fault::Unpause => service.unpause().await,
+            Fault::Disconnect => service.disconnect().await,
+            Fault::Connect => service.connect().await,
+            Fault::Restart => service.driver.restart(service).await,
+        }
+    }
+}
+
+/// A timeline of faults to inject in the services of an app, returned by `App::chaos`. Faults are
+/// scheduled with `at`, e.g pause `db` after 2s, and unpause it after 5s, and applied
+/// concurrently with the test once the schedule is started.
+pub struct Chaos {
+    app: App,
+    /// Faults to apply, as (delay from the start, service, fault).
+    steps: Vec<(Duration, String, Fault)>,
+}
+
+impl Chaos {
+    pub(crate) fn new(app: App) -> Self {
+        Self {
+            app,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Apply `fault` to `service`, `after` the schedule was started.
+    pub fn at(mut self, after: Duration, service: &str, fault: Fault) -> Self {
+        self.steps.push((after, service.to_string(), fault));
+        self
+    }
+
+    /// Start applying the faults, concurrently with the test. Fails if a fault targets a service
+    /// that is not part of the app.
+    pub fn start(mut self) -> anyhow::Result<ChaosHandle> {
+        for (_, service, _) in &self.steps {
+            self.app.service_expect(service)?;
+        }
+        // the sort is stable, so faults scheduled at the same time are applied in order
+        self.steps.sort_by_key(|(after, ..)| *after);
+        let started_at = Instant::now();
+        let handle = tokio::spawn(async move {
+            for (after, name, fault) in &self.steps {
+                tokio::time::sleep_until(started_at + *after).await;
+                let service = self.app.service_expect(name)?;
+                fault
+                    .apply(service)
+                    .await
+                    .with_context(|| format!("failed to apply {fault:?} to service `{name}`"))?;
+            }
+            Ok(())
+        });
+
+        Ok(ChaosHandle(handle))
+    }
+}
+
+/// A running chaos schedule. Dropping the handle stops applying the remaining faults.
+pub struct ChaosHandle(JoinHandle<anyhow::Result<()>>);
+
+impl ChaosHandle {
+    /// Wait for all the faults to be applied, and return the first error, if any.
+    pub async fn wait(mut self) -> anyhow::Result<()> {
+        (&mut self.0).await.context("chaos schedule panicked")?
+    }
+}
+
+impl Drop for ChaosHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
@@ -1,44 +1,176 @@
-use std::{net::IpAddr, time::Duration};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
 
-use anyhow::{bail, Context};
-use futures::{Stream, StreamExt};
+use anyhow::{anyhow, bail, Context};
+use futures::{Future, Stream, StreamExt};
 use maplit::hashmap;
 use podman_api::{
+    models::{
+        self, ContainerInspectResponseLibpod, LinuxDevice, LinuxPids, LinuxResources, PosixRlimit,
+    },
     opts::{
-        ContainerCreateOpts, ContainerDeleteOpts, ContainerLogsOpts, NetworkConnectOpts,
-        NetworkCreateOpts,
+        self, ContainerCreateOpts, ContainerDeleteOpts, ContainerListFilter, ContainerListOpts,
+        ContainerLogsOpts, ContainerStatsOpts, ExecCreateOpts, ExecStartOpts, NetworkConnectOpts,
+        NetworkCreateOpts, PullOpts, RegistryAuth, SecretCreateOpts, VolumeCreateOpts,
     },
     Podman,
 };
-use uuid::Uuid;
+use rand::Rng;
+use tokio::task::JoinHandle;
 
 use crate::{
-    emitter::LogLine,
-    resource::Resources,
-    service::{Service, ServiceConfig},
-    Network,
+    emitter::{LogLine, LogStream},
+    report::HostInfo,
+    resource::{Resources, Secret, TmpDir, Volume},
+    service::{
+        ExecOutput, PortMapping, Protocol, PullPolicy, Service, ServiceConfig, ServiceState,
+    },
+    AppConfig, Network,
 };
 
+/// Label set on all the resources created by octopod, with the id of the run as value.
+const RUN_ID_LABEL: &str = "io.octopod.run-id";
+
+/// Label set on built images, with the hash of their build context as value.
+const BUILD_HASH_LABEL: &str = "io.octopod.build-hash";
+
+/// How long to wait for a service to become healthy.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default maximum duration of a single request to the podman API.
+const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of times a request is retried after the connection to podman was lost.
+const RECONNECT_ATTEMPTS: usize = 3;
+
 #[derive(Clone)]
 pub(crate) struct Driver {
-    api: Podman,
+    api: Arc<RwLock<Podman>>,
+    addr: String,
+    /// Pull policy for services that don't set one.
+    pub pull_policy: PullPolicy,
+    /// Registry credentials for services that don't set any.
+    pub registry_auth: Option<(String, String)>,
+    /// Only follow the logs produced after the logs are requested, instead of replaying the logs
+    /// from the start of the service.
+    pub logs_since_now: bool,
+    /// Maximum duration of a single request to the podman API.
+    pub call_timeout: Duration,
 }
 
 impl Driver {
     pub fn new(addr: &str) -> anyhow::Result<Self> {
         let api = Podman::new(addr)?;
-        Ok(Self { api })
+        Ok(Self {
+            api: Arc::new(RwLock::new(api)),
+            addr: addr.to_string(),
+            pull_policy: PullPolicy::Missing,
+            registry_auth: None,
+            logs_since_now: false,
+            call_timeout: DEFAULT_CALL_TIMEOUT,
+        })
     }
 
-    pub async fn network(&self, resources: &mut Resources) -> anyhow::Result<Network> {
-        let name = Uuid::new_v4().to_string();
+    /// Address of the podman API.
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    fn api(&self) -> Podman {
+        self.api.read().unwrap().clone()
+    }
+
+    /// Returns the podman client, for use outside of the driver.
+    pub fn podman(&self) -> Podman {
+        self.api()
+    }
+
+    /// Performs the `op` request against the podman API. If the connection to podman is lost, the
+    /// client is re-created, and the request retried a bounded number of times before failing.
+    /// Each attempt fails if it takes longer than the call timeout.
+    async fn call<T, F, Fut>(&self, op: &str, f: F) -> anyhow::Result<T>
+    where
+        F: Fn(Podman) -> Fut,
+        Fut: Future<Output = podman_api::Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let res = tokio::time::timeout(self.call_timeout, f(self.api()))
+                .await
+                .map_err(|_| anyhow!("podman {op} timed out after {:?}", self.call_timeout))?;
+            match res {
+                Err(e) if is_connection_error(&e) => {
+                    if attempt == RECONNECT_ATTEMPTS {
+                        return Err(e).with_context(|| {
+                            format!("lost connection to podman at {}", self.addr)
+                        });
+                    }
+                    attempt += 1;
+                    tokio::time::sleep(Duration::from_millis(500 * attempt as u64)).await;
+                    *self.api.write().unwrap() = Podman::new(&self.addr)?;
+                }
+                res => return Ok(res?),
+            }
+        }
+    }
+
+    /// Performs the `op` request against the podman API, failing if it takes longer than the call
+    /// timeout. Unlike `call`, the request is not retried, e.g because it is bound to an exec
+    /// session.
+    async fn bounded<T>(
+        &self,
+        op: &str,
+        fut: impl Future<Output = podman_api::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let res = tokio::time::timeout(self.call_timeout, fut)
+            .await
+            .map_err(|_| anyhow!("podman {op} timed out after {:?}", self.call_timeout))?;
+        Ok(res?)
+    }
+
+    /// Create a network for an app. Any stale network with the same name is removed beforehand.
+    pub async fn network(
+        &self,
+        name: String,
+        run_id: &str,
+        config: &AppConfig,
+        resources: &Resources,
+    ) -> anyhow::Result<Network> {
+        let name_ref = &name;
+        if self
+            .call("network exists", |api| async move {
+                api.networks().get(name_ref).exists().await
+            })
+            .await?
+        {
+            self.call("network remove", |api| async move {
+                api.networks().get(name_ref).remove().await
+            })
+            .await?;
+        }
         let opts = NetworkCreateOpts::builder()
             .name(&name)
-            .dns_enabled(true)
+            .dns_enabled(config.dns_enabled)
+            .internal(config.internal_network)
+            .labels([(RUN_ID_LABEL, run_id)])
             .build();
-        self.api.networks().create(&opts).await?;
+        let opts = &opts;
+        self.call("network create", |api| async move {
+            api.networks().create(opts).await
+        })
+        .await?;
 
-        let net = Network { name };
+        let net = Network {
+            name,
+            run_id: run_id.to_string(),
+            dns_servers: config.dns_servers.clone(),
+            dns_search: config.dns_search.clone(),
+        };
         resources.register(net.clone());
 
         Ok(net)
@@ -48,56 +180,631 @@ impl Driver {
         &self,
         config: &ServiceConfig,
         net: &Network,
-        resources: &mut Resources,
+        tmp_dir: &Path,
+        resources: &Resources,
     ) -> anyhow::Result<Service> {
-        let opts = ContainerCreateOpts::builder()
-            .networks([(net.name(), hashmap! { "aliases" => vec![&config.name]})])
-            .image(&config.image)
-            .env(config.env.clone())
-            .build();
-        let resp = self.api.containers().create(&opts).await?;
-        let container = self.api.containers().get(&resp.id);
-        container.start(None).await?;
+        let mut secrets = Vec::new();
+        for (id, source) in &config.secrets {
+            let secret = self
+                .secret(
+                    &format!("{}-{}-{id}", net.name(), config.name),
+                    source,
+                    resources,
+                )
+                .await?;
+            secrets.push(models::Secret {
+                source: Some(secret.name),
+                target: Some(id.clone()),
+                gid: None,
+                mode: None,
+                uid: None,
+            });
+        }
+
+        let mut config_files = Vec::new();
+        if !config.config_files.is_empty() {
+            let dir = tempfile::Builder::new()
+                .prefix(&format!("{}-{}-", net.name(), config.name))
+                .tempdir()
+                .context("failed to create the config files directory")?;
+            for (i, (contents, container_path)) in config.config_files.iter().enumerate() {
+                let path = dir.path().join(i.to_string());
+                std::fs::write(&path, contents)
+                    .with_context(|| format!("failed to write config file `{container_path}`"))?;
+                config_files.push(models::ContainerMount {
+                    destination: Some(container_path.clone()),
+                    source: Some(path.display().to_string()),
+                    _type: Some("bind".into()),
+                    options: Some(vec!["ro".into()]),
+                    uid_mappings: None,
+                    gid_mappings: None,
+                });
+            }
+            resources.register(TmpDir(Mutex::new(Some(dir))));
+        }
+
+        let image = config.resolved_image();
+        if let Some(ref expected) = config.expect_digest {
+            self.verify_digest(&image, expected).await?;
+        }
+
+        let mut env = Vec::new();
+        for (key, value) in &config.env {
+            let value = interpolate(value, &env).with_context(|| {
+                format!("invalid value for `{key}` in service `{}`", config.name)
+            })?;
+            env.push((key.clone(), value));
+        }
+
+        let mut volumes = Vec::new();
+        for (name, path) in &config.named_volumes {
+            let volume = self
+                .volume(&format!("{}-{name}", net.name()), &net.run_id, resources)
+                .await?;
+            volumes.push(models::NamedVolume {
+                name: Some(volume.name),
+                dest: Some(path.clone()),
+                is_anonymous: None,
+                options: None,
+            });
+        }
+
+        let mut opts = ContainerCreateOpts::builder()
+            .name(format!("{}-{}", net.name(), config.name))
+            .labels([(RUN_ID_LABEL, net.run_id.as_str())])
+            .annotations(
+                config
+                    .annotations
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str())),
+            )
+            .image(image)
+            .restart_policy(config.restart_policy.unwrap_or_default())
+            .env(env)
+            .dns_server(net.dns_servers.iter().map(ToString::to_string))
+            .dns_search(net.dns_search.clone())
+            .secrets(secrets)
+            .volumes(volumes)
+            .read_only_fs(config.read_only_rootfs)
+            // podman picks a free host port when none is given
+            .portmappings(config.published_ports.iter().map(|(port, protocol)| {
+                models::PortMapping {
+                    container_port: Some(*port),
+                    protocol: Some(protocol.as_str().into()),
+                    host_ip: None,
+                    host_port: None,
+                    range: None,
+                }
+            }))
+            .mounts(
+                config
+                    .tmp_dir_mount
+                    .iter()
+                    .map(|path| models::ContainerMount {
+                        destination: Some(path.clone()),
+                        source: Some(tmp_dir.display().to_string()),
+                        _type: Some("bind".into()),
+                        options: None,
+                        uid_mappings: None,
+                        gid_mappings: None,
+                    })
+                    .chain(config_files),
+            )
+            .hosts_add(
+                config
+                    .extra_hosts
+                    .iter()
+                    .map(|(host, ip)| format!("{host}:{ip}")),
+            )
+            .sysctl(config.sysctls.clone())
+            .r_limits(config.ulimits.iter().map(|(name, soft, hard)| PosixRlimit {
+                type_: Some(name.clone()),
+                soft: Some(*soft),
+                hard: Some(*hard),
+            }))
+            .resource_limits(LinuxResources {
+                block_io: None,
+                cpu: None,
+                devices: None,
+                hugepage_limits: None,
+                memory: None,
+                network: None,
+                pids: config
+                    .pids_limit
+                    .map(|limit| LinuxPids { limit: Some(limit) }),
+                rdma: None,
+                unified: None,
+            })
+            // podman parses the device path as `host[:container]`
+            .devices(config.devices.iter().map(|(host, container)| LinuxDevice {
+                path: Some(match container {
+                    Some(container) => format!("{host}:{container}"),
+                    None => host.clone(),
+                }),
+                file_mode: None,
+                gid: None,
+                major: None,
+                minor: None,
+                type_: None,
+                uid: None,
+            }));
+        opts = if config.host_network {
+            opts.net_namespace(models::Namespace {
+                nsmode: Some("host".into()),
+                value: None,
+            })
+        } else {
+            opts.networks([(net.name(), hashmap! { "aliases" => vec![&config.name]})])
+        };
+        if let Some(ref workdir) = config.workdir {
+            opts = opts.work_dir(workdir);
+        }
+        if let Some((os, arch, variant)) = config.platform_parts() {
+            opts = opts.image_os(os);
+            if let Some(arch) = arch {
+                opts = opts.image_arch(arch);
+            }
+            if let Some(variant) = variant {
+                opts = opts.image_variant(variant);
+            }
+        }
+        for customize in &config.customizers {
+            opts = (customize.0)(opts);
+        }
+        let opts = &opts.build();
+        let resp = self
+            .call("container create", |api| async move {
+                api.containers().create(opts).await
+            })
+            .await?;
 
         let service = Service {
             name: config.name.clone(),
             id: resp.id,
             net: net.clone(),
             driver: self.clone(),
+            host_network: config.host_network,
         };
+        // register the service before starting it, so that it is cleaned up even if it fails to
+        // start.
+        resources.register(service.clone());
 
+        let id = &service.id;
+        let start = self.call("container start", |api| async move {
+            api.containers().get(id).start(None).await
+        });
+        let started = match config.start_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, start).await.map_err(|_| {
+                anyhow!(
+                    "service `{}` failed to start within {timeout:?}",
+                    config.name
+                )
+            })?,
+            None => start.await,
+        };
+        if let Err(e) = started {
+            if config.devices.is_empty() {
+                return Err(e);
+            }
+            return Err(e.context(format!(
+                "failed to start service `{}` with devices; note that device access may be \
+                unavailable under rootless podman",
+                config.name
+            )));
+        }
+
+        Ok(service)
+    }
+
+    /// Wait for the service to pass its health checks. The service may restart while starting up,
+    /// up to `max_restarts` times, after which it is considered to be crash-looping.
+    pub(crate) async fn wait_healthy(
+        &self,
+        service: &Service,
+        config: &ServiceConfig,
+    ) -> anyhow::Result<()> {
+        let started_at = Instant::now();
+        // failed probes, as (time since the first probe, failure)
+        let mut history = Vec::new();
+        let mut restarts = 0;
+        let mut interval = config.health_interval;
+        while started_at.elapsed() < HEALTH_TIMEOUT {
+            match self.probe_health(service, config).await {
+                Ok(()) => return Ok(()),
+                Err(e) => history.push((started_at.elapsed(), e)),
+            }
+
+            let state = self.service_state(service).await?;
+            restarts = state.restart_count;
+            if restarts > config.max_restarts {
+                bail!(
+                    "service `{}` is crash-looping: restarted {restarts} times while waiting for \
+                    it to become healthy\n{}",
+                    service.name,
+                    format_health_history(&history)
+                );
+            }
+            // without a restart policy, an exited service will never become healthy
+            if let (None, Some(code)) = (&config.restart_policy, state.exit_code) {
+                bail!(
+                    "service `{}` exited with code {code} while waiting for it to become \
+                    healthy\n{}",
+                    service.name,
+                    format_health_history(&history)
+                );
+            }
+
+            tokio::time::sleep(jittered(interval, config.health_jitter)).await;
+            if let Some((factor, max_interval)) = config.health_backoff {
+                interval = interval.mul_f64(factor).min(max_interval);
+            }
+        }
+
+        bail!(
+            "timed out waiting for service `{}` to become healthy (restarted {restarts} times)\n{}",
+            service.name,
+            format_health_history(&history)
+        )
+    }
+
+    /// Run the configured health checks against the service once.
+    pub(crate) async fn is_healthy(&self, service: &Service, config: &ServiceConfig) -> bool {
+        self.probe_health(service, config).await.is_ok()
+    }
+
+    /// Run the configured health checks against the service once, and describe why the service
+    /// is unhealthy, if it is.
+    async fn probe_health(&self, service: &Service, config: &ServiceConfig) -> Result<(), String> {
         if let Some((ref uri, port)) = config.health {
-            let ip = self.get_service_ip(&service).await?;
-            let url = format!("http://{ip}:{port}{uri}");
-            for i in 0..10 {
-                match reqwest::get(&url).await {
-                    Ok(resp) if resp.status().is_success() => {
-                        break;
-                    }
-                    _ if i == 9 => {
-                        bail!("Timedout waiting for service");
-                    }
-                    _ => {
-                        tokio::time::sleep(Duration::from_secs(1)).await;
-                    }
+            let (ip, port) = self
+                .health_addr(service, config, port)
+                .await
+                .map_err(|e| format!("no address to probe: {e}"))?;
+            match reqwest::get(format!("http://{ip}:{port}{uri}")).await {
+                Ok(resp) if resp.status().is_success() => (),
+                Ok(resp) => return Err(format!("GET {uri} returned {}", resp.status())),
+                Err(e) => return Err(format!("GET {uri} failed: {e}")),
+            }
+        }
+
+        if config.image_healthcheck {
+            // run the healthcheck defined by the service image
+            let result = self
+                .call("container healthcheck", |api| async move {
+                    api.containers().get(&service.id).healthcheck().await
+                })
+                .await;
+            match result {
+                Ok(result) if result.status.as_deref() == Some("healthy") => (),
+                Ok(result) => {
+                    return Err(format!(
+                        "image healthcheck reported `{}`",
+                        result.status.as_deref().unwrap_or("unknown")
+                    ))
                 }
+                Err(e) => return Err(format!("image healthcheck failed: {e}")),
             }
         }
 
-        resources.register(service.clone());
+        if let Some(ref cmd) = config.health_exec {
+            match self.exec(service, cmd, None).await {
+                Ok(output) if output.success() => (),
+                Ok(output) => {
+                    return Err(format!(
+                        "`{}` exited with code {}: {}",
+                        cmd.join(" "),
+                        output.exit_code,
+                        output.stderr.trim()
+                    ))
+                }
+                Err(e) => return Err(format!("failed to run `{}`: {e}", cmd.join(" "))),
+            }
+        }
 
-        Ok(service)
+        Ok(())
+    }
+
+    /// The address to probe the health port of the service on. Published ports are probed through
+    /// the host, since the container IP isn't always reachable from the host, e.g with rootless
+    /// podman.
+    async fn health_addr(
+        &self,
+        service: &Service,
+        config: &ServiceConfig,
+        port: u16,
+    ) -> anyhow::Result<(IpAddr, u16)> {
+        if !service.host_network && config.published_ports.contains(&(port, Protocol::Tcp)) {
+            let host_port = service.host_port(port, Protocol::Tcp).await?;
+            return Ok((Ipv4Addr::LOCALHOST.into(), host_port));
+        }
+
+        Ok((self.get_service_ip(service).await?, port))
+    }
+
+    /// Total memory used by the containers of the run, in bytes.
+    pub async fn memory_usage(&self, run_id: &str) -> anyhow::Result<u64> {
+        let opts = &ContainerListOpts::builder()
+            .filter([ContainerListFilter::LabelKeyVal(
+                RUN_ID_LABEL.into(),
+                run_id.into(),
+            )])
+            .build();
+        let containers = self
+            .call("container list", |api| async move {
+                api.containers().list(opts).await
+            })
+            .await?;
+        let ids: Vec<String> = containers.into_iter().filter_map(|c| c.id).collect();
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let opts = &ContainerStatsOpts::builder().containers(ids).build();
+        let report = self
+            .call("container stats", |api| async move {
+                api.containers().stats(opts).await
+            })
+            .await?;
+        // the report is untyped, e.g `{"Stats": [{"MemUsage": 1024, ..}, ..]}`
+        let total = report["Stats"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|stats| stats["MemUsage"].as_u64())
+            .sum();
+
+        Ok(total)
+    }
+
+    /// Detect the conditions of the environment that tests can be ignored on, with whether they
+    /// hold.
+    pub async fn host_info(&self) -> anyhow::Result<HostInfo> {
+        let info = self
+            .call("info", |api| async move { api.info().await })
+            .await?;
+        let host = info.host.context("missing podman host info")?;
+        let rootless = host
+            .security
+            .and_then(|security| security.rootless)
+            .unwrap_or(false);
+        let os = match host.distribution {
+            Some(distribution) => format!(
+                "{} {}",
+                distribution.distribution.unwrap_or_default(),
+                distribution.version.unwrap_or_default()
+            ),
+            None => host.os.unwrap_or_default(),
+        };
+
+        Ok(HostInfo {
+            podman_version: info
+                .version
+                .as_ref()
+                .and_then(|version| version.version.clone())
+                .unwrap_or_default(),
+            api_version: info
+                .version
+                .and_then(|version| version.api_version)
+                .unwrap_or_default(),
+            os,
+            cgroup_version: host.cgroup_version.unwrap_or_default(),
+            rootless,
+        })
+    }
+
+    /// Pull the image of a service, according to its pull policy.
+    /// The progress messages reported by podman are passed to `progress`.
+    pub async fn pull(
+        &self,
+        config: &ServiceConfig,
+        progress: impl Fn(&str),
+    ) -> anyhow::Result<()> {
+        let policy = match config.pull_policy.unwrap_or(self.pull_policy) {
+            PullPolicy::Always => opts::PullPolicy::Always,
+            PullPolicy::Missing => opts::PullPolicy::Missing,
+            PullPolicy::Newer => opts::PullPolicy::Newer,
+            PullPolicy::Never => return Ok(()),
+        };
+        let image = config.resolved_image();
+        let mut opts = PullOpts::builder().reference(&image).policy(policy);
+        if let Some((os, arch, variant)) = config.platform_parts() {
+            opts = opts.os(os);
+            if let Some(arch) = arch {
+                opts = opts.arch(arch);
+            }
+            if let Some(variant) = variant {
+                opts = opts.variant(variant);
+            }
+        }
+        if let Some((username, password)) = config
+            .registry_auth
+            .as_ref()
+            .or(self.registry_auth.as_ref())
+        {
+            opts.auth(
+                RegistryAuth::builder()
+                    .username(username)
+                    .password(password)
+                    .build(),
+            );
+        }
+        let opts = opts.build();
+
+        let images = self.api().images();
+        let mut reports = images.pull(&opts);
+        while let Some(report) = reports.next().await {
+            let report =
+                report.with_context(|| format!("lost connection to podman at {}", self.addr))?;
+            if let Some(error) = report.error {
+                bail!("failed to pull image `{image}`: {error}");
+            }
+            if let Some(status) = report.stream.as_deref().map(str::trim) {
+                if !status.is_empty() {
+                    progress(status);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Build the image of a service from its build context. If the build cache is enabled, and
+    /// `rebuild` isn't set, the build is skipped when the image was already built from the same
+    /// context. Returns whether the image was built.
+    pub async fn build(
+        &self,
+        config: &ServiceConfig,
+        context: &Path,
+        rebuild: bool,
+    ) -> anyhow::Result<bool> {
+        let image = &config.resolved_image();
+        let hash = hash_dir(context)
+            .with_context(|| format!("failed to read build context `{}`", context.display()))?
+            .to_string();
+        if config.build_cache && !rebuild {
+            let existing = self
+                .call("image inspect", |api| async move {
+                    api.images().get(image).inspect().await
+                })
+                .await
+                .ok()
+                .and_then(|data| data.labels)
+                .and_then(|mut labels| labels.remove(BUILD_HASH_LABEL));
+            if existing.as_deref() == Some(hash.as_str()) {
+                return Ok(false);
+            }
+        }
+
+        let opts = opts::ImageBuildOpts::builder(context.display().to_string())
+            .tag(image)
+            .labels([(BUILD_HASH_LABEL, hash.as_str())])
+            .build();
+        let images = self.api().images();
+        let mut stream = images
+            .build(&opts)
+            .with_context(|| format!("failed to build image `{image}`"))?;
+        while let Some(chunk) = stream.next().await {
+            chunk.with_context(|| format!("failed to build image `{image}`"))?;
+        }
+
+        Ok(true)
+    }
+
+    /// Check that `image` resolves to the `expected` digest.
+    async fn verify_digest(&self, image: &str, expected: &str) -> anyhow::Result<()> {
+        let data = self
+            .call("image inspect", |api| async move {
+                api.images().get(image).inspect().await
+            })
+            .await
+            .with_context(|| format!("failed to inspect image `{image}`"))?;
+        // the digest of the image manifest, and the digests it is known under in registries, as
+        // `repo@digest`.
+        let repo_digests = data.repo_digests.unwrap_or_default();
+        let mut digests = data.digest.iter().map(String::as_str).chain(
+            repo_digests
+                .iter()
+                .filter_map(|d| d.split_once('@').map(|(_, digest)| digest)),
+        );
+        if !digests.any(|digest| digest == expected) {
+            bail!(
+                "image `{image}` has digest `{}`, expected `{expected}`",
+                data.digest.as_deref().unwrap_or("unknown")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Create a secret with the content of the `source` file.
+    async fn secret(
+        &self,
+        name: &str,
+        source: &Path,
+        resources: &Resources,
+    ) -> anyhow::Result<Secret> {
+        let data = std::fs::read_to_string(source)
+            .with_context(|| format!("failed to read secret from `{}`", source.display()))?;
+        let opts = &SecretCreateOpts::builder(name).build();
+        let data = &data;
+        self.call("secret create", |api| async move {
+            api.secrets().create(opts, data.as_str()).await
+        })
+        .await?;
+
+        let secret = Secret {
+            name: name.to_string(),
+        };
+        resources.register(secret.clone());
+
+        Ok(secret)
+    }
+
+    /// Create a named volume, unless it already exists, e.g because another service of the app
+    /// mounts it as well.
+    async fn volume(
+        &self,
+        name: &str,
+        run_id: &str,
+        resources: &Resources,
+    ) -> anyhow::Result<Volume> {
+        let volume = Volume {
+            name: name.to_string(),
+        };
+        let exists = self
+            .call("volume exists", |api| async move {
+                api.volumes().get(name).exists().await
+            })
+            .await?;
+        if exists {
+            return Ok(volume);
+        }
+
+        let opts = &VolumeCreateOpts::builder()
+            .name(name)
+            .labels([(RUN_ID_LABEL, run_id)])
+            .build();
+        self.call("volume create", |api| async move {
+            api.volumes().create(opts).await
+        })
+        .await?;
+        resources.register(volume.clone());
+
+        Ok(volume)
+    }
+
+    pub async fn destroy_volume(&self, volume: &Volume) -> anyhow::Result<()> {
+        self.call("volume remove", |api| async move {
+            api.volumes().get(&volume.name).remove().await
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn destroy_secret(&self, secret: &Secret) -> anyhow::Result<()> {
+        self.call("secret delete", |api| async move {
+            api.secrets().get(&secret.name).delete().await
+        })
+        .await?;
+        Ok(())
     }
 
     pub async fn destroy_network(&self, network: &Network) -> anyhow::Result<()> {
         // remove destroy all the containers associated with the network as well
-        self.api.networks().get(network.name()).remove().await?;
+        self.call("network remove", |api| async move {
+            api.networks().get(network.name()).remove().await
+        })
+        .await?;
         Ok(())
     }
 
     pub async fn get_service_ip(&self, service: &Service) -> anyhow::Result<IpAddr> {
-        let container = self.api.containers().get(&service.id);
-        let meta = container.inspect().await?;
+        // services on the host network share the network stack of the host.
+        if service.host_network {
+            return Ok(Ipv4Addr::LOCALHOST.into());
+        }
+
+        let meta = self.inspect(service).await?;
         // TODO: error handling
         let ip = meta
             .network_settings
@@ -114,42 +821,165 @@ impl Driver {
         Ok(ip)
     }
 
-    pub async fn destroy_service(&self, service: &Service) -> anyhow::Result<()> {
-        let container = self.api.containers().get(&service.id);
-        container
-            .delete(
-                &ContainerDeleteOpts::builder()
-                    .force(true)
-                    .timeout(0)
-                    .build(),
+    async fn inspect(&self, service: &Service) -> anyhow::Result<ContainerInspectResponseLibpod> {
+        self.call("container inspect", |api| async move {
+            api.containers().get(&service.id).inspect().await
+        })
+        .await
+    }
+
+    pub async fn service_state(&self, service: &Service) -> anyhow::Result<ServiceState> {
+        let meta = self.inspect(service).await?;
+        let state = meta.state.context("missing service state")?;
+        // podman reports an exit code of 0 for containers that haven't exited
+        let exit_code = state
+            .exit_code
+            .filter(|_| state.status.as_deref() == Some("exited"));
+
+        Ok(ServiceState {
+            status: state.status.unwrap_or_default(),
+            running: state.running.unwrap_or_default(),
+            exit_code,
+            restart_count: meta.restart_count.unwrap_or_default() as u32,
+            oom_killed: state.oom_killed.unwrap_or_default(),
+            health: state.health.and_then(|h| h.status),
+        })
+    }
+
+    pub async fn service_ports(&self, service: &Service) -> anyhow::Result<Vec<PortMapping>> {
+        let meta = self.inspect(service).await?;
+        let ports = meta
+            .network_settings
+            .and_then(|settings| settings.ports)
+            .unwrap_or_default();
+        let mut mappings = Vec::new();
+        // ports are keyed by `port/protocol`, e.g `5432/tcp`
+        for (key, bindings) in ports {
+            let (port, protocol) = key.split_once('/').unwrap_or((key.as_str(), "tcp"));
+            let container_port = port
+                .parse()
+                .with_context(|| format!("invalid container port `{key}`"))?;
+            let protocol = Protocol::parse(protocol)
+                .with_context(|| format!("unsupported protocol for port `{key}`"))?;
+            let host_ports: Vec<u16> = bindings
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|binding| binding.host_port.as_deref()?.parse().ok())
+                .collect();
+            if host_ports.is_empty() {
+                mappings.push(PortMapping {
+                    container_port,
+                    host_port: None,
+                    protocol,
+                });
+            }
+            for host_port in host_ports {
+                mappings.push(PortMapping {
+                    container_port,
+                    host_port: Some(host_port),
+                    protocol,
+                });
+            }
+        }
+        mappings.sort_by_key(|mapping| {
+            (
+                mapping.container_port,
+                mapping.protocol.as_str(),
+                mapping.host_port,
             )
-            .await?;
+        });
+
+        Ok(mappings)
+    }
+
+    pub async fn service_env(&self, service: &Service) -> anyhow::Result<Vec<(String, String)>> {
+        let meta = self.inspect(service).await?;
+        let env = meta
+            .config
+            .and_then(|c| c.env)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|var| match var.split_once('=') {
+                Some((k, v)) => (k.to_string(), v.to_string()),
+                None => (var, String::new()),
+            })
+            .collect();
+
+        Ok(env)
+    }
+
+    pub async fn service_annotations(
+        &self,
+        service: &Service,
+    ) -> anyhow::Result<HashMap<String, String>> {
+        let meta = self.inspect(service).await?;
+        Ok(meta.config.and_then(|c| c.annotations).unwrap_or_default())
+    }
+
+    pub async fn destroy_service(&self, service: &Service) -> anyhow::Result<()> {
+        let opts = &ContainerDeleteOpts::builder()
+            .force(true)
+            .timeout(0)
+            .build();
+        self.call("container delete", |api| async move {
+            api.containers().get(&service.id).delete(opts).await
+        })
+        .await?;
 
         Ok(())
     }
 
-    pub(crate) fn logs(&self, service: &Service) -> impl Stream<Item = LogLine> {
+    /// Stream the logs of a service. If `follow` is set, the returned handle must be aborted to
+    /// stop following the logs once they are not needed anymore. Otherwise, the stream ends with
+    /// the logs produced so far.
+    /// If `since_now` is set, only the logs produced from now on are streamed.
+    pub(crate) fn logs(
+        &self,
+        service: &Service,
+        follow: bool,
+        since_now: bool,
+    ) -> (JoinHandle<()>, impl Stream<Item = LogLine> + Unpin) {
+        let since = (follow && (since_now || self.logs_since_now)).then(SystemTime::now);
+        self.logs_since(service, follow, since)
+    }
+
+    /// Like `logs`, but only streams the logs produced after `since`, if set.
+    pub(crate) fn logs_since(
+        &self,
+        service: &Service,
+        follow: bool,
+        since: Option<SystemTime>,
+    ) -> (JoinHandle<()>, impl Stream<Item = LogLine> + Unpin) {
         let name = service.name.clone();
-        let container = self.api.containers().get(&service.id);
+        let container = self.api().containers().get(&service.id);
+        let mut opts = ContainerLogsOpts::builder()
+            .stderr(true)
+            .stdout(true)
+            .follow(follow);
+        if let Some(since) = since {
+            let since = since
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            opts = opts.since(format!("{}.{:09}", since.as_secs(), since.subsec_nanos()));
+        }
+        let opts = opts.build();
         let (snd, recv) = tokio::sync::mpsc::unbounded_channel();
-        tokio::spawn(async move {
-            let mut stream = container.logs(
-                &ContainerLogsOpts::builder()
-                    .stderr(true)
-                    .stdout(true)
-                    .follow(true)
-                    .build(),
-            );
+        let handle = tokio::spawn(async move {
+            let mut stream = container.logs(&opts);
 
             while let Some(chunk) = stream.next().await {
-                let data = match chunk.unwrap() {
-                    podman_api::conn::TtyChunk::StdOut(data) => data,
-                    podman_api::conn::TtyChunk::StdErr(data) => data,
-                    _ => Vec::new(),
+                // the container is gone, there is nothing more to follow.
+                let Ok(chunk) = chunk else { break };
+                let (data, stream) = match chunk {
+                    podman_api::conn::TtyChunk::StdOut(data) => (data, LogStream::Stdout),
+                    podman_api::conn::TtyChunk::StdErr(data) => (data, LogStream::Stderr),
+                    _ => (Vec::new(), LogStream::Stdout),
                 };
                 let line = LogLine {
                     name: name.clone(),
                     data: String::from_utf8(data).unwrap(),
+                    timestamp: SystemTime::now(),
+                    stream,
                 };
 
                 if let Err(_) = snd.send(line) {
@@ -158,41 +988,231 @@ impl Driver {
             }
         });
 
-        tokio_stream::wrappers::UnboundedReceiverStream::new(recv)
+        (
+            handle,
+            tokio_stream::wrappers::UnboundedReceiverStream::new(recv),
+        )
     }
 
-    pub(crate) async fn disconnect(&self, service: &Service) -> anyhow::Result<()> {
-        self.api
-            .containers()
-            .get(&service.id)
-            .disconnect(&service.net.name, true)
+    pub(crate) async fn exec(
+        &self,
+        service: &Service,
+        cmd: &[String],
+        workdir: Option<&str>,
+    ) -> anyhow::Result<ExecOutput> {
+        let mut opts = ExecCreateOpts::builder()
+            .command(cmd)
+            .attach_stdout(true)
+            .attach_stderr(true);
+        if let Some(workdir) = workdir {
+            opts = opts.working_dir(workdir);
+        }
+        let opts = &opts.build();
+        let exec = self
+            .call("exec create", |api| async move {
+                api.containers().get(&service.id).create_exec(opts).await
+            })
             .await?;
 
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let start_opts = ExecStartOpts::builder().build();
+        // only starting the command is bounded, the command itself may run for as long as it needs
+        if let Some(mut output) = self.bounded("exec start", exec.start(&start_opts)).await? {
+            while let Some(chunk) = output.next().await {
+                match chunk? {
+                    podman_api::conn::TtyChunk::StdOut(data) => stdout.extend(data),
+                    podman_api::conn::TtyChunk::StdErr(data) => stderr.extend(data),
+                    _ => (),
+                }
+            }
+        }
+
+        let exit_code = self.bounded("exec inspect", exec.inspect()).await?["ExitCode"]
+            .as_i64()
+            .context("missing exec exit code")?;
+
+        Ok(ExecOutput {
+            exit_code,
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        })
+    }
+
+    pub(crate) async fn disconnect(&self, service: &Service) -> anyhow::Result<()> {
+        self.call("network disconnect", |api| async move {
+            api.containers()
+                .get(&service.id)
+                .disconnect(&service.net.name, true)
+                .await
+        })
+        .await?;
+
         Ok(())
     }
 
     pub(crate) async fn connect(&self, service: &Service) -> anyhow::Result<()> {
-        self.api
-            .containers()
-            .get(&service.id)
-            .connect(
-                &service.net.name,
-                &NetworkConnectOpts::builder()
-                    .aliases([&service.name])
-                    .build(),
-            )
-            .await?;
+        let opts = &NetworkConnectOpts::builder()
+            .aliases([&service.name])
+            .build();
+        self.call("network connect", |api| async move {
+            api.containers()
+                .get(&service.id)
+                .connect(&service.net.name, opts)
+                .await
+        })
+        .await?;
+
+        Ok(())
+    }
 
+    pub(crate) async fn restart(&self, service: &Service) -> anyhow::Result<()> {
+        self.call("container restart", |api| async move {
+            api.containers().get(&service.id).restart().await
+        })
+        .await?;
         Ok(())
     }
 
     pub(crate) async fn pause(&self, service: &Service) -> anyhow::Result<()> {
-        self.api.containers().get(&service.id).pause().await?;
+        self.call("container pause", |api| async move {
+            api.containers().get(&service.id).pause().await
+        })
+        .await?;
         Ok(())
     }
 
     pub(crate) async fn unpause(&self, service: &Service) -> anyhow::Result<()> {
-        self.api.containers().get(&service.id).unpause().await?;
+        self.call("container unpause", |api| async move {
+            api.containers().get(&service.id).unpause().await
+        })
+        .await?;
         Ok(())
     }
 }
+
+/// Whether `e` was caused by podman not finding the requested resource, e.g because it was already
+/// removed. Failures to reach podman, e.g a missing socket, are not "not found" errors.
+pub(crate) fn is_not_found(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        matches!(
+            e.downcast_ref::<podman_api::Error>(),
+            Some(podman_api::Error::Fault { code, .. }) if code.as_u16() == 404
+        )
+    })
+}
+
+/// Whether `e` was caused by a failure to communicate with podman, rather than by podman
+/// rejecting the request.
+fn is_connection_error(e: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if e.is::<std::io::Error>() {
+            return true;
+        }
+        source = e.source();
+    }
+
+    false
+}
+
+/// Substitutes the `${VAR}` and `${VAR:-default}` references in `value`, like compose does.
+/// Variables are looked up in `env` first, then in the host environment. The default is used if
+/// the variable is unset or empty. `$$` is an escaped `$`.
+fn interpolate(value: &str, env: &[(String, String)]) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(r) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('{') {
+            let end = r
+                .find('}')
+                .with_context(|| format!("unterminated variable in `{value}`"))?;
+            let (name, default) = match r[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&r[..end], None),
+            };
+            let resolved = env
+                .iter()
+                .rev()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+                .or_else(|| std::env::var(name).ok())
+                .filter(|v| default.is_none() || !v.is_empty())
+                .or_else(|| default.map(ToString::to_string))
+                .with_context(|| format!("undefined variable `{name}` in `{value}`"))?;
+            out.push_str(&resolved);
+            rest = &r[end + 1..];
+        } else {
+            out.push('$');
+        }
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+/// Hashes the paths and contents of the files under `dir`, with FNV-1a, which is stable across
+/// runs.
+fn hash_dir(dir: &Path) -> std::io::Result<u64> {
+    fn visit(root: &Path, dir: &Path, hash: &mut u64) -> std::io::Result<()> {
+        let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        // the order of the entries returned by `read_dir` is unspecified
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                visit(root, &path, hash)?;
+            } else {
+                let relative = path.strip_prefix(root).unwrap_or(&path);
+                fnv1a(hash, relative.to_string_lossy().as_bytes());
+                fnv1a(hash, &std::fs::read(&path)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn fnv1a(hash: &mut u64, bytes: &[u8]) {
+        for byte in bytes {
+            *hash ^= *byte as u64;
+            *hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    let mut hash = 0xcbf29ce484222325;
+    visit(dir, dir, &mut hash)?;
+    Ok(hash)
+}
+
+/// Randomly vary `interval` by up to `jitter` of its value.
+fn jittered(interval: Duration, jitter: f64) -> Duration {
+    if jitter == 0.0 {
+        return interval;
+    }
+    let factor = 1.0 + rand::thread_rng().gen_range(-jitter..=jitter);
+    interval.mul_f64(factor)
+}
+
+/// Formats the failed health probes, collapsing consecutive identical failures.
+fn format_health_history(history: &[(Duration, String)]) -> String {
+    let mut out = String::from("health check history:");
+    let mut i = 0;
+    while i < history.len() {
+        let (at, ref failure) = history[i];
+        let count = history[i..]
+            .iter()
+            .take_while(|(_, f)| f == failure)
+            .count();
+        out.push_str(&format!("\n  +{:.1}s: {failure}", at.as_secs_f64()));
+        if count > 1 {
+            out.push_str(&format!(" ({count} times)"));
+        }
+        i += count;
+    }
+
+    out
+}
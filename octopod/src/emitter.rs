@@ -1,108 +1,450 @@
-use std::{fmt, time::Instant};
+use std::{
+    fmt,
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
 use termion::color;
 
 pub struct Emitter {
     results: Vec<TestResult>,
     log_all: bool,
+    log_format: LogFormat,
     started_at: Instant,
+    out: Box<dyn Write + Send>,
+    /// Whether the output is colored.
+    color: bool,
+    /// Width of the test name column.
+    width: usize,
+    /// Print every failure, instead of grouping identical failures.
+    expand_failures: bool,
+    /// Number of tests emitted so far, by outcome. Passing tests are only retained in `results`
+    /// when their logs are shown, so they can't be counted from there.
+    counts: Counts,
+}
+
+#[derive(Default)]
+struct Counts {
+    passed: usize,
+    failed: usize,
+    ignored: usize,
+    flaky: usize,
+}
+
+/// How log lines are printed in the test report.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Colored service name column, aligned on the longest service name.
+    #[default]
+    Aligned,
+    /// Plain `[service] line` prefix, without colors or alignment.
+    Plain,
+}
+
+/// A cloneable handle to the writer test reports are written to. Defaults to stdout.
+#[derive(Clone)]
+pub struct Output {
+    out: Arc<Mutex<dyn Write + Send>>,
+    /// Whether the output is a terminal that understands ANSI escape codes.
+    is_terminal: bool,
+    /// Whether the output is stdout, rather than a custom writer.
+    is_stdout: bool,
+}
+
+impl Output {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Arc::new(Mutex::new(out)),
+            is_terminal: false,
+            is_stdout: false,
+        }
+    }
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self {
+            is_terminal: stdout_is_ansi_terminal(),
+            is_stdout: true,
+            ..Self::new(io::stdout())
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.lock().unwrap().flush()
+    }
+}
+
+/// An in-memory output, used to buffer the output of a suite when suites run concurrently.
+#[derive(Clone, Default)]
+pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+    /// Takes the content of the buffer, leaving it empty.
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How an emitter prints the results of a suite.
+pub(crate) struct EmitterOptions {
+    /// Print the logs of all the tests, including the passing ones.
+    pub log_all: bool,
+    pub log_format: LogFormat,
+    /// Print every failure, instead of grouping identical failures.
+    pub expand_failures: bool,
+    /// Whether the output is colored.
+    pub color: bool,
+    /// Width of the test name column.
+    pub width: usize,
+}
+
+/// Identifies the test a result is for.
+#[derive(Clone, Copy)]
+pub(crate) struct TestId<'a> {
+    /// Name of the app the test ran against.
+    pub app: &'a str,
+    /// Fully qualified name of the test.
+    pub name: &'a str,
+    /// Name the test is reported under.
+    pub display_name: &'a str,
 }
 
 impl Emitter {
-    pub fn new(log_all: bool) -> Self {
+    pub fn new(options: EmitterOptions, out: Box<dyn Write + Send>) -> Self {
+        let EmitterOptions {
+            log_all,
+            log_format,
+            expand_failures,
+            color,
+            width,
+        } = options;
         Self {
             results: Vec::new(),
             log_all,
+            log_format,
             started_at: Instant::now(),
+            out,
+            color,
+            width,
+            expand_failures,
+            counts: Counts::default(),
         }
     }
 
-    pub fn emit(&mut self, result: TestResult) {
-        print!("{:.<75}", result.name);
+    pub fn start_suite(&mut self, app: &str, count: usize) -> io::Result<()> {
+        writeln!(self.out, "running {count} tests on {app}:")
+    }
+
+    /// Emit the result of a test. The logs of a passing test are printed in the summary if
+    /// `show_logs` is set, or if all logs are printed.
+    pub fn emit(&mut self, result: TestResult, show_logs: bool) -> io::Result<()> {
+        write!(
+            self.out,
+            "{:.<width$}",
+            result.display_name,
+            width = self.width
+        )?;
         match result.outcome {
             TestOutcome::Pass => {
-                println!("{}ok{}", color::Fg(color::Green), color::Fg(color::Reset));
-                if self.log_all {
+                self.counts.passed += 1;
+                writeln!(self.out, "{}", paint(self.color, "ok", color::Green))?;
+                if self.log_all || show_logs {
                     self.results.push(result)
+                } else if !result.checkpoints.is_empty() {
+                    // checkpoints are reported whatever the outcome
+                    self.results.push(TestResult {
+                        logs: None,
+                        ..result
+                    })
                 }
             }
             TestOutcome::Fail { .. } => {
-                println!("{}FAIL{}", color::Fg(color::Red), color::Fg(color::Reset));
+                self.counts.failed += 1;
+                writeln!(self.out, "{}", paint(self.color, "FAIL", color::Red))?;
                 self.results.push(result);
             }
             TestOutcome::Ignore => {
-                println!(
-                    "{}ignored{}",
-                    color::Fg(color::Yellow),
-                    color::Fg(color::Reset)
-                );
+                self.counts.ignored += 1;
+                write!(self.out, "{}", paint(self.color, "ignored", color::Yellow))?;
+                match result.ignore_reason {
+                    Some(ref reason) => writeln!(self.out, ", {reason}")?,
+                    None => writeln!(self.out)?,
+                }
+                self.results.push(result);
+            }
+            TestOutcome::Flaky { .. } => {
+                self.counts.flaky += 1;
+                writeln!(self.out, "{}", paint(self.color, "FLAKY", color::Yellow))?;
                 self.results.push(result);
             }
         }
+
+        Ok(())
     }
-}
 
-impl Drop for Emitter {
-    fn drop(&mut self) {
-        let mut passed = 0;
-        let mut failed = 0;
-        let mut ignored = 0;
+    fn summary(&mut self) -> io::Result<()> {
+        // flaky tests are reported in their own section, after the other tests.
+        self.results
+            .sort_by_key(|result| matches!(result.outcome, TestOutcome::Flaky { .. }));
+        // failures grouped by error, as (normalized error, first failed test, other failed tests)
+        let mut groups: Vec<(String, &str, Vec<&str>)> = Vec::new();
         for result in &self.results {
             match result.outcome {
                 TestOutcome::Pass => {
-                    passed += 1;
-                    println!("=== Test ok: {} ===", result.name);
+                    writeln!(self.out, "=== Test ok: {} ===", result.display_name)?;
                 }
                 TestOutcome::Fail { ref output } => {
-                    failed += 1;
-                    println!("=== Test failure: {} ===", result.name);
-                    println!("{output}");
+                    let error = normalize_error(output);
+                    let group = groups.iter_mut().find(|(e, ..)| *e == error);
+                    match group {
+                        // the error was printed with the first test of the group
+                        Some((_, first, others)) if !self.expand_failures => {
+                            others.push(&result.display_name);
+                            writeln!(
+                                self.out,
+                                "=== Test failure: {} (same error as {first}) ===",
+                                result.display_name
+                            )?;
+                        }
+                        _ => {
+                            if !self.expand_failures {
+                                groups.push((error, &result.display_name, Vec::new()));
+                            }
+                            writeln!(self.out, "=== Test failure: {} ===", result.display_name)?;
+                            writeln!(self.out, "{output}")?;
+                        }
+                    }
                 }
-                TestOutcome::Ignore => {
-                    ignored += 1;
+                TestOutcome::Ignore => (),
+                TestOutcome::Flaky { ref output } => {
+                    writeln!(self.out, "=== Flaky test: {} ===", result.display_name)?;
+                    writeln!(self.out, "{output}")?;
                 }
             }
+            for (label, logs) in &result.checkpoints {
+                writeln!(self.out, "Logs at checkpoint `{label}`:")?;
+                write_logs(&mut self.out, logs, self.log_format, self.color)?;
+            }
             if let Some(logs) = &result.logs {
-                println!("Logs:");
-                for entry in logs {
-                    println!("{entry}");
-                }
+                writeln!(self.out, "Logs:")?;
+                write_logs(&mut self.out, logs, self.log_format, self.color)?;
+            }
+        }
+
+        for (_, first, others) in &groups {
+            if !others.is_empty() {
+                writeln!(
+                    self.out,
+                    "=== {} more tests failed with the same error as {first}: {} ===",
+                    others.len(),
+                    others.join(", ")
+                )?;
             }
+        }
 
-            println!(
-                "test result: {}. {} passed; {} ignored; {} failed; finished in {:.3?}",
+        let Counts {
+            passed,
+            failed,
+            ignored,
+            flaky,
+        } = self.counts;
+        if passed + failed + ignored + flaky > 0 {
+            writeln!(
+                self.out,
+                "test result: {}. {} passed; {} ignored; {} failed; {} flaky; finished in {:.3?}",
                 if failed == 0 {
-                    format!("{}ok{}", color::Fg(color::Green), color::Fg(color::Reset))
+                    paint(self.color, "ok", color::Green)
                 } else {
-                    format!(
-                        "{}failure{}",
-                        color::Fg(color::Red),
-                        color::Fg(color::Reset)
-                    )
+                    paint(self.color, "failure", color::Red)
                 },
                 passed,
                 ignored,
                 failed,
+                flaky,
                 self.started_at.elapsed()
-            );
+            )?;
+        }
+
+        self.out.flush()
+    }
+}
+
+/// Normalizes an error message, so that errors differing only by numbers, e.g ports, ids or
+/// durations, compare equal.
+fn normalize_error(error: &str) -> String {
+    let mut normalized = String::with_capacity(error.len());
+    for c in error.chars() {
+        if !c.is_ascii_digit() {
+            normalized.push(c);
+        } else if !normalized.ends_with('#') {
+            normalized.push('#');
+        }
+    }
+
+    normalized
+}
+
+/// When to color the output. The same policy applies to the test report and the service logs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color the output if it is a terminal, and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    /// Always color the output, even when it is redirected, e.g for tools that render ANSI colors.
+    Always,
+    /// Never color the output.
+    Never,
+}
+
+impl ColorChoice {
+    /// Reads the color choice from cargo's `CARGO_TERM_COLOR` (`auto`, `always` or `never`).
+    pub(crate) fn from_env() -> Self {
+        match std::env::var("CARGO_TERM_COLOR").as_deref() {
+            Ok("always") => ColorChoice::Always,
+            Ok("never") => ColorChoice::Never,
+            _ => ColorChoice::Auto,
+        }
+    }
+
+    /// Whether to color `out`.
+    pub(crate) fn enabled(self, out: &Output) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => std::env::var_os("NO_COLOR").is_none() && out.is_terminal,
+        }
+    }
+}
+
+/// Whether stdout is a tty that can render colors. CI shells often set `TERM=dumb`, or don't set
+/// `TERM` at all, in which case escape codes would garble the output.
+fn stdout_is_ansi_terminal() -> bool {
+    let dumb = match std::env::var("TERM") {
+        Ok(term) => term.is_empty() || term == "dumb",
+        Err(_) => true,
+    };
+    !dumb && termion::is_tty(&io::stdout())
+}
+
+/// Width of the dotted test name column in `out`, adapted to the width of the terminal when
+/// writing to stdout.
+pub(crate) fn name_width(out: &Output) -> usize {
+    if !out.is_stdout {
+        return 75;
+    }
+    match termion::terminal_size() {
+        // leave room for the outcome
+        Ok((cols, _)) => (cols as usize).saturating_sub(10).clamp(40, 120),
+        Err(_) => 75,
+    }
+}
+
+fn write_logs(
+    out: &mut dyn Write,
+    logs: &[LogLine],
+    format: LogFormat,
+    color: bool,
+) -> io::Result<()> {
+    let width = logs.iter().map(|l| l.name.len()).max().unwrap_or_default();
+    for entry in logs {
+        match format {
+            LogFormat::Aligned if color => writeln!(out, "{entry:width$}")?,
+            LogFormat::Aligned => writeln!(out, "{entry:#width$}")?,
+            LogFormat::Plain => writeln!(out, "{entry:#}")?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Wraps `text` in `c`, if `enabled`.
+fn paint(enabled: bool, text: &str, c: impl color::Color) -> String {
+    if enabled {
+        format!("{}{text}{}", color::Fg(c), color::Fg(color::Reset))
+    } else {
+        text.to_string()
+    }
+}
+
+impl Drop for Emitter {
+    fn drop(&mut self) {
+        if let Err(e) = self.summary() {
+            eprintln!("error writing test summary: {e}");
         }
     }
 }
 
 pub struct TestResult {
-    name: String,
-    outcome: TestOutcome,
-    logs: Option<Vec<LogLine>>,
+    pub(crate) app: String,
+    pub(crate) name: String,
+    pub(crate) display_name: String,
+    pub(crate) outcome: TestOutcome,
+    pub(crate) logs: Option<Vec<LogLine>>,
+    pub(crate) duration: Duration,
+    /// Why the test was ignored, if it was.
+    pub(crate) ignore_reason: Option<String>,
+    /// Logs captured by the test with `App::checkpoint_logs`.
+    pub(crate) checkpoints: Vec<Checkpoint>,
 }
 
+/// Logs captured with `App::checkpoint_logs`, as (label, logs).
+pub(crate) type Checkpoint = (String, Vec<LogLine>);
+
+#[derive(Clone, Debug)]
 pub struct LogLine {
-    pub name: String,
-    pub data: String,
+    pub(crate) name: String,
+    pub(crate) data: String,
+    pub(crate) timestamp: SystemTime,
+    pub(crate) stream: LogStream,
+}
+
+/// The output stream a log line was written to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogStream {
+    Stdout,
+    Stderr,
 }
 
 impl LogLine {
-    /// picks unique color for this line name
-    fn name_color(&self) -> color::Rgb {
+    /// Name of the service that emitted this line.
+    pub fn service(&self) -> &str {
+        &self.name
+    }
+
+    pub fn data(&self) -> &str {
+        &self.data
+    }
+
+    /// Time at which the line was received.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    pub fn stream(&self) -> LogStream {
+        self.stream
+    }
+
+    /// picks a color for this line name, from a palette of colors legible on both dark and light
+    /// terminals. The same name always gets the same color.
+    fn name_color(&self) -> color::AnsiValue {
         // CRC hash
         let h = self.name.chars().fold(0u32, |mut h, c| {
             let highorder = h & 0xf8000000;
@@ -111,21 +453,42 @@ impl LogLine {
             h = h ^ c as u32;
             h
         });
-        let bytes = h.to_be_bytes();
-        color::Rgb(bytes[0], bytes[1], bytes[2])
+        color::AnsiValue(NAME_PALETTE[h as usize % NAME_PALETTE.len()])
     }
 }
 
+/// 256-color codes of the colors service names are printed in.
+const NAME_PALETTE: [u8; 12] = [33, 37, 70, 136, 166, 125, 99, 31, 172, 29, 161, 62];
+
+/// Each line is prefixed with the name of the service. The width of the name column can be set
+/// with the formatter width (e.g `{line:15}`), and defaults to the length of the name. The
+/// alternate flag (`{line:#}`) prints a plain `[service]` prefix instead, without colors. With
+/// both (`{line:#15}`), the name column is aligned, without colors.
 impl fmt::Display for LogLine {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for line in self.data.lines() {
-            write!(
-                f,
-                "{}{:<10}|{} {line}",
-                color::Fg(self.name_color()),
-                self.name,
-                color::Fg(color::Reset)
-            )?;
+        // lines written to stderr are marked with a `!` separator
+        let sep = match self.stream {
+            LogStream::Stdout => '|',
+            LogStream::Stderr => '!',
+        };
+        let width = f.width().unwrap_or_default();
+        for (i, line) in self.data.lines().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            if f.alternate() && width == 0 {
+                write!(f, "[{}]{sep} {line}", self.name)?;
+            } else if f.alternate() {
+                write!(f, "{:<width$}{sep} {line}", self.name)?;
+            } else {
+                write!(
+                    f,
+                    "{}{:<width$}{sep}{} {line}",
+                    color::Fg(self.name_color()),
+                    self.name,
+                    color::Fg(color::Reset)
+                )?;
+            }
         }
 
         Ok(())
@@ -133,33 +496,121 @@ impl fmt::Display for LogLine {
 }
 
 impl TestResult {
-    pub fn pass(name: &str, logs: Option<Vec<LogLine>>) -> Self {
+    pub(crate) fn pass(id: TestId, duration: Duration, logs: Option<Vec<LogLine>>) -> Self {
         Self {
-            name: name.to_string(),
+            app: id.app.to_string(),
+            name: id.name.to_string(),
+            display_name: id.display_name.to_string(),
             outcome: TestOutcome::Pass,
             logs,
+            duration,
+            ignore_reason: None,
+            checkpoints: Vec::new(),
         }
     }
 
-    pub fn fail(name: &str, e: String, logs: Option<Vec<LogLine>>) -> Self {
+    pub(crate) fn fail(
+        id: TestId,
+        e: String,
+        duration: Duration,
+        logs: Option<Vec<LogLine>>,
+    ) -> Self {
         Self {
-            name: name.to_string(),
+            app: id.app.to_string(),
+            name: id.name.to_string(),
+            display_name: id.display_name.to_string(),
             outcome: TestOutcome::Fail { output: e },
             logs,
+            duration,
+            ignore_reason: None,
+            checkpoints: Vec::new(),
         }
     }
 
-    pub fn ignore(name: &str) -> Self {
+    pub(crate) fn ignore(id: TestId, reason: Option<String>) -> Self {
         Self {
-            name: name.to_string(),
+            app: id.app.to_string(),
+            name: id.name.to_string(),
+            display_name: id.display_name.to_string(),
             outcome: TestOutcome::Ignore,
             logs: None,
+            duration: Duration::ZERO,
+            ignore_reason: reason,
+            checkpoints: Vec::new(),
         }
     }
+
+    /// Name of the app the test ran against.
+    pub fn app(&self) -> &str {
+        &self.app
+    }
+
+    /// Fully qualified name of the test.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name the test is reported under, the fully qualified name unless set with `name = "..."`.
+    pub fn display_name(&self) -> &str {
+        &self.display_name
+    }
+
+    pub fn outcome(&self) -> &TestOutcome {
+        &self.outcome
+    }
+
+    /// Why the test was ignored, if it was ignored for a reason other than the `ignore` flag.
+    pub fn ignore_reason(&self) -> Option<&str> {
+        self.ignore_reason.as_deref()
+    }
+
+    /// Time taken to run the test body.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Logs captured from the services while the test was running.
+    pub fn logs(&self) -> Option<&[LogLine]> {
+        self.logs.as_deref()
+    }
+
+    /// Logs captured by the test with `App::checkpoint_logs`, along with their label.
+    pub fn checkpoints(&self) -> impl Iterator<Item = (&str, &[LogLine])> {
+        self.checkpoints
+            .iter()
+            .map(|(label, logs)| (label.as_str(), logs.as_slice()))
+    }
 }
 
-enum TestOutcome {
+#[derive(Clone, Debug)]
+pub enum TestOutcome {
     Pass,
-    Fail { output: String },
+    Fail {
+        output: String,
+    },
     Ignore,
+    /// The test both passed and failed over repeated iterations.
+    Flaky {
+        output: String,
+    },
+}
+
+impl TestOutcome {
+    /// Short name of the outcome, used in machine-readable outputs.
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            TestOutcome::Pass => "pass",
+            TestOutcome::Fail { .. } => "fail",
+            TestOutcome::Ignore => "ignore",
+            TestOutcome::Flaky { .. } => "flaky",
+        }
+    }
+
+    /// The failure message, if the test failed.
+    pub(crate) fn message(&self) -> Option<&str> {
+        match self {
+            TestOutcome::Fail { output } | TestOutcome::Flaky { output } => Some(output),
+            TestOutcome::Pass | TestOutcome::Ignore => None,
+        }
+    }
 }
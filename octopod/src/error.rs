@@ -0,0 +1,41 @@
+use std::{error::Error, fmt};
+
+/// Error returned by the entry points of octopod, categorized so that callers can react
+/// differently to, e.g, podman being unreachable and a configuration mistake.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum OctopodError {
+    /// Octopod couldn't talk to podman, e.g because the podman service isn't running. These
+    /// errors are often transient.
+    Connection(anyhow::Error),
+    /// The apps or tests are misconfigured, e.g a test uses an unknown app.
+    Config(anyhow::Error),
+    /// The results of the run couldn't be written, e.g to the summary file.
+    Output(anyhow::Error),
+}
+
+impl OctopodError {
+    pub(crate) fn config(msg: impl fmt::Display + fmt::Debug + Send + Sync + 'static) -> Self {
+        Self::Config(anyhow::Error::msg(msg))
+    }
+}
+
+impl fmt::Display for OctopodError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OctopodError::Connection(e) => write!(f, "failed to communicate with podman: {e:#}"),
+            OctopodError::Config(e) => write!(f, "invalid configuration: {e:#}"),
+            OctopodError::Output(e) => write!(f, "failed to write the results of the run: {e:#}"),
+        }
+    }
+}
+
+impl Error for OctopodError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OctopodError::Connection(e) | OctopodError::Config(e) | OctopodError::Output(e) => {
+                Some(e.as_ref())
+            }
+        }
+    }
+}
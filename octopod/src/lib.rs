@@ -1,62 +1,143 @@
 #[doc(hidden)]
 pub mod sealed;
 
+mod builder;
+mod chaos;
 mod driver;
 mod emitter;
+mod error;
+mod report;
+mod reporter;
 mod resource;
 mod service;
+mod timings;
 
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
 
-use anyhow::Context;
+use anyhow::{anyhow, bail, Context};
 use driver::Driver;
-use emitter::{Emitter, LogLine, TestResult};
-use futures::{stream::SelectAll, Stream, StreamExt};
-use resource::Resources;
+use emitter::{Buffer, Checkpoint, Emitter, EmitterOptions, Output, TestId};
+use futures::{future::BoxFuture, stream::SelectAll, Future, Stream, StreamExt};
+use resource::{Cleanup, Resources, TmpDir};
 use sealed::{TestDecl, TestFn};
+use timings::Timings;
+use tokio::{
+    sync::watch,
+    task::{JoinError, JoinHandle},
+    time::Instant,
+};
+use uuid::Uuid;
 
+pub use builder::OctopodBuilder;
+pub use chaos::{Chaos, ChaosHandle, Fault};
+pub use emitter::{ColorChoice, LogFormat, LogLine, LogStream, TestOutcome, TestResult};
+pub use error::OctopodError;
 pub use octopod_macros::test;
-pub use service::{Service, ServiceConfig};
+pub use podman_api;
+pub use report::{HostInfo, RunReport, TestRecord};
+pub use reporter::{Reporter, WebReporter};
+pub use service::{
+    ExecOutput, PortMapping, Protocol, PullPolicy, Service, ServiceConfig, ServiceState,
+};
+
+/// Time given to a cancelled test to clean up, before it is aborted.
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Time given to the resources of a suite to be freed once the run timed out, before they are
+/// left behind.
+const CLEANUP_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Interval at which the memory usage of the services is sampled.
+const MEMORY_SAMPLING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time an init container has to complete, unless it sets a `start_timeout`.
+const INIT_CONTAINER_TIMEOUT: Duration = Duration::from_secs(300);
 
 pub struct Octopod {
     driver: Driver,
     suites: Vec<TestSuite>,
     log_all: bool,
+    output: Output,
+    run_id: String,
+    reporters: Vec<Box<dyn Reporter>>,
+    max_concurrency: usize,
+    timeout: Option<Duration>,
+    log_format: LogFormat,
+    summary_path: Option<PathBuf>,
+    require_tests: bool,
+    repeat: usize,
+    bail_after: Option<usize>,
+    expand_failures: bool,
+    /// User-defined conditions for `ignore_if`.
+    conditions: HashMap<String, bool>,
+    max_total_memory: Option<u64>,
+    color: ColorChoice,
+    fail_on_ignored: bool,
+    log_archive: Option<PathBuf>,
+    rebuild_images: bool,
+    /// Index of the shard to run, and total number of shards.
+    shard: Option<(usize, usize)>,
+    timings_path: Option<PathBuf>,
 }
 
 impl Octopod {
     /// Initialize Octopod, sets up the connection to the podman API, and collects all tests.
     /// An error is returned if an app is used within a test, and is not registered on
-    /// initialization.
-    pub fn init(podman_addr: &str, apps: Vec<AppConfig>) -> anyhow::Result<Self> {
-        let mut suites: HashMap<String, TestSuite> = HashMap::new();
-        for config in apps {
-            let name = config.name.clone();
-            let suite = TestSuite::new(config.clone());
-            suites.insert(name, suite);
-        }
-
-        for decl in inventory::iter::<TestDecl>() {
-            let test = Test {
-                f: decl.f,
-                name: decl.name.into(),
-                ignore: decl.ignore,
-            };
+    /// initialization, or if several apps have the same name.
+    pub fn init(podman_addr: &str, apps: Vec<AppConfig>) -> Result<Self, OctopodError> {
+        Self::with_filters(podman_addr, apps, &[])
+    }
 
-            suites
-                .get_mut(decl.app)
-                .with_context(|| format!("unknown app `{}` in test `{}`", decl.app, decl.name))?
-                .tests
-                .push(test);
-        }
+    /// Returns a builder, to configure octopod fluently before collecting the tests.
+    pub fn builder() -> OctopodBuilder {
+        OctopodBuilder::default()
+    }
 
-        let suites = suites.into_values().collect();
-        let driver = Driver::new(podman_addr)?;
+    /// Like `init`, but only collects the tests whose name, or display name, contains one of
+    /// `filters`, if any.
+    /// Apps left without tests are skipped.
+    fn with_filters(
+        podman_addr: &str,
+        apps: Vec<AppConfig>,
+        filters: &[String],
+    ) -> Result<Self, OctopodError> {
+        let suites = collect_suites(apps, inventory::iter::<TestDecl>(), filters)?;
+        let driver = Driver::new(podman_addr).map_err(OctopodError::Connection)?;
 
         Ok(Self {
             driver,
             suites,
             log_all: false,
+            output: Output::default(),
+            run_id: Uuid::new_v4().simple().to_string()[..8].to_string(),
+            reporters: Vec::new(),
+            max_concurrency: 1,
+            timeout: None,
+            log_format: LogFormat::default(),
+            summary_path: None,
+            require_tests: false,
+            repeat: 1,
+            bail_after: None,
+            expand_failures: false,
+            conditions: HashMap::new(),
+            max_total_memory: None,
+            color: ColorChoice::from_env(),
+            fail_on_ignored: false,
+            log_archive: None,
+            rebuild_images: false,
+            shard: None,
+            timings_path: None,
         })
     }
 
@@ -66,28 +147,550 @@ impl Octopod {
         self
     }
 
-    pub async fn run(self) -> anyhow::Result<bool> {
-        let mut success = true;
-        for suite in self.suites {
-            let mut resources = Resources::default();
-            match suite.run(&self.driver, &mut resources, self.log_all).await {
-                Err(e) => {
-                    eprintln!("error running test suite: {e}");
+    /// Only print the logs of failed tests, and of the tests marked with `show_logs`. This is the
+    /// default.
+    pub fn log_on_fail_only(mut self) -> Self {
+        self.log_all = false;
+        self
+    }
+
+    /// Set how the service logs are printed in the report.
+    pub fn log_format(mut self, format: LogFormat) -> Self {
+        self.log_format = format;
+        self
+    }
+
+    /// Set when to color the report and the service logs. Defaults to the value of
+    /// `CARGO_TERM_COLOR`, or `ColorChoice::Auto`.
+    pub fn color(mut self, choice: ColorChoice) -> Self {
+        self.color = choice;
+        self
+    }
+
+    /// Write test reports to `out` instead of stdout.
+    pub fn output(mut self, out: impl Write + Send + 'static) -> Self {
+        self.output = Output::new(out);
+        self
+    }
+
+    /// Set the id of the run. The run id is mixed into the names and labels of all the resources
+    /// created by octopod, so that concurrent runs don't interfere with each other. Networks are
+    /// named after the run id, the app and the test, so a stable run id gives stable,
+    /// human-readable network names. A stale network with the same name is removed when the app
+    /// is instantiated.
+    ///
+    /// By default, a random run id is generated.
+    pub fn run_id(mut self, run_id: impl Into<String>) -> Self {
+        self.run_id = run_id.into();
+        self
+    }
+
+    /// Add a reporter, notified of test results and logs as they happen.
+    pub fn reporter(mut self, reporter: impl Reporter + 'static) -> Self {
+        self.reporters.push(Box::new(reporter));
+        self
+    }
+
+    /// Run up to `n` test suites, and up to `n` tests overall, concurrently. The output of each
+    /// suite is buffered, and written once the suite has completed. Defaults to 1. See
+    /// `AppConfig::max_concurrency` to run the tests of a suite concurrently.
+    pub fn max_concurrency(mut self, n: usize) -> Self {
+        self.max_concurrency = n.max(1);
+        self
+    }
+
+    /// Bound the duration of the whole run, including building and pulling the images, and
+    /// instantiating the apps. When the timeout expires, the running tests are aborted, the
+    /// remaining tests are marked as failed without being run, and the resources are cleaned up,
+    /// within a grace period.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set when to pull the images of services that don't set a pull policy. Defaults to
+    /// `PullPolicy::Missing`.
+    pub fn default_pull_policy(mut self, policy: PullPolicy) -> Self {
+        self.driver.pull_policy = policy;
+        self
+    }
+
+    /// Set the credentials used to pull the images of services that don't set any.
+    pub fn registry_auth(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.driver.registry_auth = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Only capture the logs the services produce while the test body runs, instead of replaying
+    /// their logs from startup. Keeps the captured logs short for services with verbose startups.
+    pub fn logs_from_test_start(mut self) -> Self {
+        self.driver.logs_since_now = true;
+        self
+    }
+
+    /// Fail the individual podman calls that take longer than `timeout` to complete, so that a
+    /// stuck podman daemon surfaces as an error instead of a hang. Image pulls and builds, the
+    /// output of the commands run in the services, and followed logs are not bounded, as they
+    /// legitimately take long. Defaults to 30s.
+    pub fn podman_call_timeout(mut self, timeout: Duration) -> Self {
+        self.driver.call_timeout = timeout;
+        self
+    }
+
+    /// Write a JSON summary of the run to `path` once the run is complete.
+    pub fn summary_json(mut self, path: impl Into<PathBuf>) -> Self {
+        self.summary_path = Some(path.into());
+        self
+    }
+
+    /// Fail the run if a registered app has no tests. An app without tests is often the sign of a
+    /// typo in the `app = "..."` attribute of the tests meant to target it.
+    pub fn require_tests_per_app(mut self) -> Self {
+        self.require_tests = true;
+        self
+    }
+
+    /// Run each test `n` times, against a fresh instance of the app each time. A test that passes
+    /// some iterations and fails others is reported as flaky. Flaky tests don't fail the run.
+    /// Defaults to 1.
+    pub fn repeat(mut self, n: usize) -> Self {
+        self.repeat = n.max(1);
+        self
+    }
+
+    /// Stop running tests once `n` tests have failed, across all the suites. The remaining tests
+    /// are reported as ignored.
+    pub fn bail_after(mut self, n: usize) -> Self {
+        self.bail_after = Some(n.max(1));
+        self
+    }
+
+    /// Print the details of every failed test in the summary. By default, tests failing with the
+    /// same error as a previous test are only listed, to make common causes stand out.
+    pub fn expand_failures(mut self) -> Self {
+        self.expand_failures = true;
+        self
+    }
+
+    /// Define a condition that tests can be ignored on, with `#[octopod::test(ignore_if = "...")]`,
+    /// along with whether it holds in this environment. The built-in conditions are detected
+    /// when the run starts:
+    /// - `rootless` and `root`: whether podman runs rootless.
+    /// - `cgroupv1` and `cgroupv2`: the cgroup version of the podman host.
+    /// - `linux`, `macos` and `windows`: the OS the tests run on.
+    ///
+    /// User-defined conditions take precedence over the built-in ones. A test ignored on an
+    /// unknown condition fails.
+    pub fn condition(mut self, name: impl Into<String>, holds: bool) -> Self {
+        self.conditions.insert(name.into(), holds);
+        self
+    }
+
+    /// Abort the run if the memory used by all the services of the run exceeds `bytes`. The
+    /// memory usage is sampled periodically. When the budget is exceeded, the running tests are
+    /// aborted, the remaining tests are marked as failed without being run, and the resources are
+    /// cleaned up.
+    pub fn max_total_memory(mut self, bytes: u64) -> Self {
+        self.max_total_memory = Some(bytes);
+        self
+    }
+
+    /// Consider the run failed if any test was ignored, to prevent ignored tests from rotting.
+    pub fn fail_on_ignored(mut self) -> Self {
+        self.fail_on_ignored = true;
+        self
+    }
+
+    /// Archive the logs of every test, whatever its outcome, under `path`, as
+    /// `<path>/<app>/<test>/<service>.log`, along with the result of the test in
+    /// `<path>/<app>/<test>/result.json`. The archive is meant to be uploaded as a CI artifact.
+    pub fn log_archive(mut self, path: impl Into<PathBuf>) -> Self {
+        self.log_archive = Some(path.into());
+        self
+    }
+
+    /// Build the images of the services again, even if their build context didn't change. See
+    /// `ServiceConfig::image_from_build_cache`.
+    pub fn rebuild_images(mut self) -> Self {
+        self.rebuild_images = true;
+        self
+    }
+
+    /// Split the tests in `total` shards, and only run the tests of shard `index`, starting from
+    /// 0, e.g to spread the tests over several CI machines. The tests are assigned to the shards
+    /// deterministically, so that every test is run by exactly one shard. With a timings file,
+    /// the shards are balanced by the expected duration of their tests, otherwise the tests are
+    /// assigned to the shards in turn, by name.
+    pub fn shard(mut self, index: usize, total: usize) -> Self {
+        self.shard = Some((index, total));
+        self
+    }
+
+    /// Record the duration of each test to `path` after the run, and use the durations recorded
+    /// by previous runs to run the slowest tests first, and to balance the shards. Tests that
+    /// have no recorded duration are expected to take the median duration.
+    pub fn timings_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.timings_path = Some(path.into());
+        self
+    }
+
+    /// The collected tests, e.g to split the tests across machines, without running them.
+    pub fn tests(&self) -> Vec<TestInfo> {
+        let mut tests: Vec<_> = self
+            .suites
+            .iter()
+            .flat_map(|suite| {
+                suite.tests.iter().map(|test| TestInfo {
+                    name: test.name.clone(),
+                    display_name: test.display_name.map(ToString::to_string),
+                    app: suite.app.name.clone(),
+                    ignored: test.ignore,
+                    ignore_if: test.ignore_if.map(ToString::to_string),
+                })
+            })
+            .collect();
+        tests.sort_by(|a, b| a.name.cmp(&b.name));
+        tests
+    }
+
+    pub async fn run(mut self) -> Result<RunReport, OctopodError> {
+        let suites = std::mem::take(&mut self.suites);
+        if self.require_tests {
+            let mut empty: Vec<_> = suites
+                .iter()
+                .filter(|suite| suite.tests.is_empty())
+                .map(|suite| format!("`{}`", suite.app.name))
+                .collect();
+            if !empty.is_empty() {
+                empty.sort();
+                return Err(OctopodError::config(format!(
+                    "no tests found for app(s) {}, check the `app = \"...\"` attribute of the tests",
+                    empty.join(", ")
+                )));
+            }
+        }
+        let mut timings = match self.timings_path {
+            Some(ref path) => Some(
+                Timings::load(path)
+                    .with_context(|| format!("failed to read timings from `{}`", path.display()))
+                    .map_err(OctopodError::Config)?,
+            ),
+            None => None,
+        };
+        let mut suites = match self.shard {
+            Some((index, total)) => shard(suites, index, total, timings.as_ref())?,
+            None => suites,
+        };
+        if let Some(ref timings) = timings {
+            sort_slowest_first(&mut suites, timings);
+        }
+        let started_at = Instant::now();
+        let deadline = self.timeout.map(|timeout| started_at + timeout);
+        let host = self
+            .driver
+            .host_info()
+            .await
+            .context("failed to query the podman host info")
+            .map_err(OctopodError::Connection)?;
+        if let Err(e) = writeln!(self.output.clone(), "running on {host}") {
+            eprintln!("error writing host info: {e}");
+        }
+        if within(deadline, self.build_images(&suites)).await.is_none() {
+            eprintln!("run timed out while building images");
+        }
+        if within(deadline, self.pull_images(&suites)).await.is_none() {
+            eprintln!("run timed out while pulling images");
+        }
+        let mut conditions = host.conditions();
+        conditions.extend(self.conditions.clone());
+        let state = RunState {
+            buffered: self.max_concurrency > 1,
+            deadline,
+            records: Mutex::default(),
+            failures: AtomicUsize::new(0),
+            conditions,
+            abort: watch::channel(None).0,
+            permits: tokio::sync::Semaphore::new(self.max_concurrency),
+        };
+        let suites = futures::stream::iter(suites)
+            .map(|suite| self.run_suite(suite, &state))
+            .buffer_unordered(self.max_concurrency)
+            .fold(true, |success, s| async move { success & s });
+        let success = tokio::select! {
+            success = suites => success && state.abort.borrow().is_none(),
+            _ = self.monitor_memory(&state) => unreachable!("the memory monitor never completes"),
+        };
+
+        for reporter in &self.reporters {
+            reporter.finish().await;
+        }
+
+        let mut report = RunReport {
+            run_id: self.run_id,
+            success,
+            duration: started_at.elapsed(),
+            records: state.records.into_inner().unwrap(),
+            host,
+        };
+        if self.fail_on_ignored && report.ignored_count() > 0 {
+            report.success = false;
+        }
+
+        if let Err(e) = writeln!(
+            self.output.clone(),
+            "finished in {:.3?} (cumulative test time {:.3?}, speedup {:.1}x)",
+            report.duration,
+            report.cumulative_duration(),
+            report.speedup()
+        ) {
+            eprintln!("error writing run summary: {e}");
+        }
+
+        if let Some(ref path) = self.summary_path {
+            report
+                .write_json(path, self.driver.addr())
+                .with_context(|| format!("failed to write summary to `{}`", path.display()))
+                .map_err(OctopodError::Output)?;
+        }
+
+        if let (Some(timings), Some(path)) = (&mut timings, &self.timings_path) {
+            timings.record(&report.records);
+            timings
+                .save(path)
+                .with_context(|| format!("failed to write timings to `{}`", path.display()))
+                .map_err(OctopodError::Output)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Run only the test named `full_name`, e.g `my_tests::db::test_insert`, instantiating only
+    /// its app. Fails if there is no such test.
+    pub async fn run_test(mut self, full_name: &str) -> Result<RunReport, OctopodError> {
+        let mut suites = std::mem::take(&mut self.suites);
+        for suite in &mut suites {
+            suite.tests.retain(|test| test.name == full_name);
+        }
+        suites.retain(|suite| !suite.tests.is_empty());
+        if suites.is_empty() {
+            return Err(OctopodError::config(format!("no test named `{full_name}`")));
+        }
+        self.suites = suites;
+
+        self.run().await
+    }
+
+    /// Abort the run if the containers of the run use more memory than `max_total_memory`. Never
+    /// completes.
+    async fn monitor_memory(&self, state: &RunState) {
+        let Some(max) = self.max_total_memory else {
+            return futures::future::pending().await;
+        };
+        loop {
+            tokio::time::sleep(MEMORY_SAMPLING_INTERVAL).await;
+            match self.driver.memory_usage(&self.run_id).await {
+                Ok(usage) if usage > max => {
+                    state.abort.send_replace(Some(format!(
+                        "services use {usage} bytes of memory, over the budget of {max} bytes"
+                    )));
+                    return futures::future::pending().await;
                 }
-                Ok(s) => success &= s,
+                Ok(_) => (),
+                Err(e) => eprintln!("error sampling memory usage: {e}"),
             }
+        }
+    }
 
-            resources.cleanup(&self.driver).await;
+    /// Build the images of the services that are built from a context, before running the tests.
+    async fn build_images(&self, suites: &[TestSuite]) {
+        let mut built = HashSet::new();
+        let configs = suites
+            .iter()
+            .flat_map(|suite| suite.app.init_containers.iter().chain(&suite.app.services));
+        for config in configs {
+            let Some(ref context) = config.build_context else {
+                continue;
+            };
+            if !built.insert(config.resolved_image()) {
+                continue;
+            }
+            if let Err(e) = self
+                .driver
+                .build(config, context, self.rebuild_images)
+                .await
+            {
+                eprintln!("error building image for service `{}`: {e:#}", config.name);
+            }
         }
+    }
 
-        Ok(success)
+    /// Pull the images of all the services before running the tests.
+    async fn pull_images(&self, suites: &[TestSuite]) {
+        let mut pulled = HashSet::new();
+        let configs = suites
+            .iter()
+            .flat_map(|suite| suite.app.init_containers.iter().chain(&suite.app.services))
+            // built images are not in a registry
+            .filter(|config| config.build_context.is_none());
+        for config in configs {
+            if !pulled.insert((config.resolved_image(), config.platform.clone())) {
+                continue;
+            }
+            let image = config.resolved_image();
+            let progress = |status: &str| {
+                for reporter in &self.reporters {
+                    reporter.pull_progress(&image, status);
+                }
+            };
+            if let Err(e) = self.driver.pull(config, progress).await {
+                eprintln!("error pulling image for service `{}`: {e}", config.name);
+            }
+        }
     }
+
+    /// Runs a suite, and returns whether all its tests were successful.
+    async fn run_suite(&self, suite: TestSuite, state: &RunState) -> bool {
+        let mut success = true;
+        let resources = Resources::new(suite.app.teardown_order.clone());
+        let buffer = Buffer::default();
+        let buffered = state.buffered;
+        let out: Box<dyn Write + Send> = if buffered {
+            Box::new(buffer.clone())
+        } else {
+            Box::new(self.output.clone())
+        };
+        let color = self.color.enabled(&self.output);
+        let options = EmitterOptions {
+            log_all: self.log_all,
+            log_format: self.log_format,
+            expand_failures: self.expand_failures,
+            color,
+            width: emitter::name_width(&self.output),
+        };
+        let mut emitter = Emitter::new(options, out);
+        let name = suite.app.name.clone();
+        // tests aborted by the timeout are given a grace period to clean up, and be reported
+        let run = suite.run(self, state, &resources, &mut emitter);
+        match within(state.deadline.map(|d| d + CANCEL_GRACE_PERIOD), run).await {
+            None => {
+                eprintln!("test suite `{name}` timed out");
+                success = false;
+            }
+            Some(Err(e)) => {
+                eprintln!("error running test suite `{name}`: {e}");
+            }
+            Some(Ok(s)) => success &= s,
+        }
+
+        let cleanup_deadline = state
+            .deadline
+            .map(|d| d + CANCEL_GRACE_PERIOD + CLEANUP_GRACE_PERIOD);
+        if within(cleanup_deadline, resources.cleanup(&self.driver))
+            .await
+            .is_none()
+        {
+            eprintln!("timed out freeing the resources of test suite `{name}`");
+        }
+
+        // the summary is written when the emitter is dropped
+        drop(emitter);
+        if buffered {
+            if let Err(e) = self.output.clone().write_all(&buffer.take()) {
+                eprintln!("error writing output of test suite `{name}`: {e}");
+            }
+        }
+
+        success
+    }
+}
+
+/// State shared by the suites during a run.
+struct RunState {
+    /// Whether the output of each suite is buffered.
+    buffered: bool,
+    deadline: Option<Instant>,
+    records: Mutex<Vec<TestRecord>>,
+    /// Number of failed tests so far.
+    failures: AtomicUsize,
+    /// Conditions tests can be ignored on, with whether they hold.
+    conditions: HashMap<String, bool>,
+    /// Set with the reason, when the run is aborted.
+    abort: watch::Sender<Option<String>>,
+    /// Permits to run a test, bounding the number of tests running at once.
+    permits: tokio::sync::Semaphore,
 }
 
 struct Test {
     f: &'static dyn TestFn,
+    /// Fully qualified name of the test.
     name: String,
+    /// Name the test is reported under, if it differs from its fully qualified name.
+    display_name: Option<&'static str>,
     ignore: bool,
+    /// Print the logs of the test even if it passes.
+    show_logs: bool,
+    /// Condition under which the test is ignored.
+    ignore_if: Option<&'static str>,
+    /// Environment variables set on all the services of the app for this test.
+    env: &'static [(&'static str, &'static str)],
+}
+
+/// A collected test, returned by `Octopod::tests`.
+#[derive(Clone, Debug)]
+pub struct TestInfo {
+    name: String,
+    display_name: Option<String>,
+    app: String,
+    ignored: bool,
+    ignore_if: Option<String>,
+}
+
+impl TestInfo {
+    /// Fully qualified name of the test.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name the test is reported under, if set with `name = "..."`.
+    pub fn display_name(&self) -> Option<&str> {
+        self.display_name.as_deref()
+    }
+
+    /// Name of the app the test runs against.
+    pub fn app(&self) -> &str {
+        &self.app
+    }
+
+    /// Whether the test is marked as ignored.
+    pub fn ignored(&self) -> bool {
+        self.ignored
+    }
+
+    /// Condition the test is ignored on, if any.
+    pub fn ignore_if(&self) -> Option<&str> {
+        self.ignore_if.as_deref()
+    }
+}
+
+impl Test {
+    /// Name the test is reported under.
+    fn display_name(&self) -> &str {
+        self.display_name.unwrap_or(&self.name)
+    }
+}
+
+/// What an app is instantiated with for a test.
+struct Instance<'a> {
+    network_name: String,
+    run_id: &'a str,
+    /// Set to true when the test is cancelled.
+    cancelled: watch::Receiver<bool>,
+    /// Environment variables set on all the services of the app.
+    env: &'a [(&'a str, &'a str)],
 }
 
 struct TestSuite {
@@ -103,83 +706,687 @@ impl TestSuite {
         }
     }
 
+    fn test_id<'a>(&'a self, test: &'a Test) -> TestId<'a> {
+        TestId {
+            app: &self.app.name,
+            name: &test.name,
+            display_name: test.display_name(),
+        }
+    }
+
+    /// Instantiates the app. The services are pushed to `started` as they are created, so that
+    /// their logs can be retrieved if the app fails to instantiate.
     async fn instantiate_app(
         &self,
         driver: &Driver,
-        resources: &mut Resources,
+        resources: &Resources,
+        instance: Instance<'_>,
+        started: &mut Vec<Service>,
     ) -> anyhow::Result<App> {
-        let network = driver.network(resources).await?;
+        let Instance {
+            network_name,
+            run_id,
+            cancelled,
+            env,
+        } = instance;
+        let network = driver
+            .network(network_name, run_id, &self.app, resources)
+            .await?;
+        let tmp_dir = tempfile::Builder::new()
+            .prefix(&format!("{}-", network.name()))
+            .tempdir()
+            .context("failed to create the app temporary directory")?;
+        let tmp_path = tmp_dir.path().to_path_buf();
+        resources.register(TmpDir(Mutex::new(Some(tmp_dir))));
+        for config in &self.app.init_containers {
+            self.run_init_container(driver, config, &network, &tmp_path, resources)
+                .await?;
+        }
+        let configs: Vec<_> = self
+            .app
+            .services
+            .iter()
+            .map(|config| config.clone().override_env(env))
+            .collect();
         let mut services = HashMap::new();
-        for config in &self.app.services {
-            let service = driver.service(config, &network, resources).await?;
+        for config in &configs {
+            let service = driver
+                .service(config, &network, &tmp_path, resources)
+                .await?;
+            started.push(service.clone());
             services.insert(config.name.clone(), service);
         }
 
-        Ok(App { services })
+        // once all the services are started, wait for them to pass their health checks
+        let checks = configs
+            .iter()
+            .filter(|config| config.has_health_check())
+            .map(|config| driver.wait_healthy(&services[&config.name], config));
+        futures::future::try_join_all(checks).await?;
+
+        for config in &configs {
+            let service = &services[&config.name];
+            for cmd in &config.post_start {
+                let output = service.exec(cmd).await.with_context(|| {
+                    format!("failed to run post-start command in `{}`", config.name)
+                })?;
+                if !output.success() {
+                    bail!(
+                        "post-start command `{}` failed in `{}` with code {}:\n{}{}",
+                        cmd.join(" "),
+                        config.name,
+                        output.exit_code,
+                        output.stdout,
+                        output.stderr
+                    );
+                }
+            }
+        }
+
+        // connectivity is checked by resolving the service names
+        if self.app.verify_connectivity && self.app.dns_enabled {
+            self.verify_connectivity(&services).await?;
+        }
+
+        Ok(App {
+            services,
+            configs,
+            network,
+            driver: driver.clone(),
+            resources: resources.clone(),
+            cancelled,
+            tmp_dir: tmp_path,
+            checkpoints: Default::default(),
+            checkpointed_at: Default::default(),
+        })
+    }
+
+    /// Run an init container to completion, and fail if it doesn't exit successfully.
+    async fn run_init_container(
+        &self,
+        driver: &Driver,
+        config: &ServiceConfig,
+        network: &Network,
+        tmp_dir: &Path,
+        resources: &Resources,
+    ) -> anyhow::Result<()> {
+        let container = driver
+            .service(config, network, tmp_dir, resources)
+            .await
+            .with_context(|| format!("failed to start init container `{}`", config.name))?;
+        let timeout = config.start_timeout.unwrap_or(INIT_CONTAINER_TIMEOUT);
+        let deadline = Instant::now() + timeout;
+        let code = loop {
+            let state = container.state().await?;
+            match state.exit_code {
+                Some(code) if !state.running => break Some(code),
+                _ if Instant::now() >= deadline => break None,
+                _ => tokio::time::sleep(Duration::from_millis(200)).await,
+            }
+        };
+
+        let error = match code {
+            Some(0) => return Ok(()),
+            Some(code) => format!("exited with code {code}"),
+            None => format!("did not complete within {timeout:?}"),
+        };
+        let (_, logs) = driver.logs(&container, false, false);
+        let logs: Vec<_> = logs.map(|line| format!("{line:#}")).collect().await;
+        bail!(
+            "init container `{}` {error}:\n{}",
+            config.name,
+            logs.join("\n")
+        );
+    }
+
+    /// Check that each service can resolve the services it depends on.
+    async fn verify_connectivity(&self, services: &HashMap<String, Service>) -> anyhow::Result<()> {
+        for config in &self.app.services {
+            let service = &services[&config.name];
+            for target in &config.depends_on {
+                if !services.contains_key(target) {
+                    bail!(
+                        "service `{}` depends on unknown service `{target}`",
+                        config.name
+                    );
+                }
+
+                let probe = format!("getent hosts {target} || nslookup {target}");
+                let output = service
+                    .exec(["sh", "-c", &probe])
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "failed to check connectivity from `{}` to `{target}`",
+                            config.name
+                        )
+                    })?;
+                if !output.success() {
+                    bail!(
+                        "service `{}` cannot reach `{target}` on the network",
+                        config.name
+                    );
+                }
+            }
+        }
+
+        Ok(())
     }
 
     /// Returns whether all the tests were successful
     async fn run(
         self,
-        driver: &Driver,
-        resources: &mut Resources,
-        log_all: bool,
+        octopod: &Octopod,
+        state: &RunState,
+        resources: &Resources,
+        emitter: &mut Emitter,
     ) -> anyhow::Result<bool> {
         let mut success = true;
-        let mut emitter = Emitter::new(log_all);
-        println!("running {} tests on {}:", self.tests.len(), self.app.name);
-        for Test { name, f, ignore } in &self.tests {
-            if *ignore {
-                emitter.emit(TestResult::ignore(name));
-                continue;
+        // the app shared by the tests, when it is reset between tests instead of instantiated for
+        // each test.
+        let shared = tokio::sync::Mutex::new(None);
+        // a shared app can't be used by several tests at once
+        let concurrency = match self.app.reset {
+            Some(_) => 1,
+            None => self.app.max_concurrency,
+        };
+        emitter.start_suite(&self.app.name, self.tests.len())?;
+        // results are reported in the order of the tests, whatever order they complete in
+        let mut results = futures::stream::iter(&self.tests)
+            .map(|test| self.run_one(octopod, state, resources, test, &shared))
+            .buffered(concurrency);
+        while let Some(res) = results.next().await {
+            let (test, result) = res?;
+            if let TestOutcome::Fail { .. } = result.outcome {
+                // at least one test failed
+                success = false;
+                state.failures.fetch_add(1, Ordering::Relaxed);
             }
+            self.report(octopod, state, emitter, result, test.show_logs)?;
+        }
 
-            let app = self.instantiate_app(driver, resources).await?;
-            let mut log_stream = app.logs(driver);
-            let fut = f.call(app);
-            //FIXME: Maybe we should fork here, and collect stdout
-            let mut test_fut = tokio::spawn(fut);
-            let mut logs = Vec::new();
-            loop {
-                tokio::select! {
-                    res = &mut test_fut => {
-                        let result = match res {
-                            Ok(_) => TestResult::pass(name, Some(logs)),
-                            Err(e) => {
-                                let msg = match e.try_into_panic() {
-                                    Ok(panic) => {
-                                        if let Some(e) = panic.downcast_ref::<&str>() {
-                                            e.to_string()
-                                        } else if let Ok(e) = panic.downcast::<String>() {
-                                            *e
-                                        } else {
-                                            "task panicked with no message".into()
-                                        }
-                                    }
-                                    Err(e) => e.to_string(),
-                                };
-                                // at least one test failed
-                                success = false;
-                                TestResult::fail(name, msg, Some(logs))
-                            }
-                        };
-                        emitter.emit(result);
-                        break;
+        Ok(success)
+    }
+
+    /// Runs all the iterations of a test, unless it is ignored.
+    async fn run_one<'a>(
+        &self,
+        octopod: &Octopod,
+        state: &RunState,
+        resources: &Resources,
+        test: &'a Test,
+        shared: &tokio::sync::Mutex<Option<App>>,
+    ) -> anyhow::Result<(&'a Test, TestResult)> {
+        let reason = if test.ignore {
+            Some(None)
+        } else if octopod
+            .bail_after
+            .is_some_and(|n| state.failures.load(Ordering::Relaxed) >= n)
+        {
+            Some(Some("too many failures".to_string()))
+        } else if let Some(condition) = test.ignore_if {
+            match state.conditions.get(condition) {
+                Some(true) => Some(Some(condition.to_string())),
+                Some(false) => None,
+                None => {
+                    let msg = format!("unknown `ignore_if` condition `{condition}`");
+                    let result = TestResult::fail(self.test_id(test), msg, Duration::ZERO, None);
+                    return Ok((test, result));
+                }
+            }
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return Ok((test, TestResult::ignore(self.test_id(test), reason)));
+        }
+
+        // bounds the number of tests running at once across the run
+        let _permit = state.permits.acquire().await?;
+        let mut results = Vec::new();
+        for iteration in 0..octopod.repeat {
+            if state
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+                || state.abort.borrow().is_some()
+            {
+                break;
+            }
+            // a test overriding the environment needs an app of its own
+            let result = match self.app.reset {
+                Some(_) if test.env.is_empty() => {
+                    let mut shared = shared.lock().await;
+                    self.run_test(octopod, state, resources, test, iteration, &mut shared)
+                        .await?
+                }
+                _ => {
+                    self.run_test(octopod, state, resources, test, iteration, &mut None)
+                        .await?
+                }
+            };
+            results.push(result);
+        }
+
+        let result = if results.is_empty() {
+            let msg = match *state.abort.borrow() {
+                Some(ref reason) => format!("not run: {reason}"),
+                None => "not run: run timed out".into(),
+            };
+            TestResult::fail(self.test_id(test), msg, Duration::ZERO, None)
+        } else {
+            merge_iterations(results)
+        };
+
+        Ok((test, result))
+    }
+
+    /// Runs a single iteration of a test against a fresh instance of the app.
+    async fn run_test(
+        &self,
+        octopod: &Octopod,
+        state: &RunState,
+        resources: &Resources,
+        test: &Test,
+        iteration: usize,
+        shared: &mut Option<App>,
+    ) -> anyhow::Result<TestResult> {
+        let driver = &octopod.driver;
+        let run_id = &octopod.run_id;
+        let deadline = state.deadline;
+        let name = test.display_name();
+        // the apps of previous iterations live until the end of the suite, so each iteration gets
+        // a network of its own
+        let network_name = resource_name(&[
+            "octopod",
+            run_id,
+            &self.app.name,
+            &test.name,
+            &iteration.to_string(),
+        ]);
+        let (cancel, cancelled) = watch::channel(false);
+        let mut started = Vec::new();
+        let reused = match shared.take() {
+            Some(app) => self.reset_app(app).await,
+            None => None,
+        };
+        let is_reused = reused.is_some();
+        let instantiated = match reused {
+            Some(mut app) => {
+                app.cancelled = cancelled;
+                Ok(app)
+            }
+            None => {
+                let instance = Instance {
+                    network_name,
+                    run_id,
+                    cancelled,
+                    env: test.env,
+                };
+                let instantiate = self.instantiate_app(driver, resources, instance, &mut started);
+                within(deadline, instantiate)
+                    .await
+                    .unwrap_or_else(|| Err(anyhow!("run timed out")))
+            }
+        };
+        let app = match instantiated {
+            Ok(app) => app,
+            // report the failure as a test failure, so that the suite carries on with the next
+            // tests.
+            Err(e) => {
+                let mut logs = Vec::new();
+                for service in &started {
+                    let (_, stream) = driver.logs(service, false, false);
+                    logs.extend(stream.collect::<Vec<_>>().await);
+                }
+                let msg = format!("failed to instantiate app: {e:#}");
+                return Ok(TestResult::fail(
+                    self.test_id(test),
+                    msg,
+                    Duration::ZERO,
+                    Some(logs),
+                ));
+            }
+        };
+        if self.app.reset.is_some() {
+            shared.replace(app.clone());
+        }
+        let services: Vec<Service> = app.services.values().cloned().collect();
+        // a reused app has already logged during the previous tests
+        let (log_handles, mut log_stream) = app.logs(driver, is_reused);
+        let checkpoints = app.checkpoints.clone();
+        let fut = test.f.call(app);
+        let started_at = Instant::now();
+        //FIXME: Maybe we should fork here, and collect stdout
+        let mut test_fut = tokio::spawn(fut);
+        let timeout = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => futures::future::pending().await,
+            }
+        };
+        let mut abort = state.abort.subscribe();
+        let aborted = async move {
+            if abort.borrow().is_none() {
+                let _ = abort.changed().await;
+            }
+            abort.borrow().clone().unwrap_or_default()
+        };
+        // resolves with the reason the test must be stopped
+        let stop = async {
+            tokio::select! {
+                _ = timeout => "run timed out".to_string(),
+                reason = aborted => reason,
+            }
+        };
+        tokio::pin!(stop);
+        let mut logs = Vec::new();
+        let res = loop {
+            tokio::select! {
+                res = &mut test_fut => break res.map_err(panic_message),
+                reason = &mut stop => {
+                    // give the test a chance to clean up before aborting it
+                    let _ = cancel.send(true);
+                    if tokio::time::timeout(CANCEL_GRACE_PERIOD, &mut test_fut).await.is_err() {
+                        test_fut.abort();
                     }
-                    Some(entry) = log_stream.next() => {
-                        logs.push(entry);
+                    break Err(format!("test aborted: {reason}"));
+                }
+                Some(entry) = log_stream.next() => {
+                    for reporter in &octopod.reporters {
+                        reporter.log_line(&self.app.name, name, &entry);
                     }
+                    logs.push(entry);
                 }
             }
+        };
+        let duration = started_at.elapsed();
+        // stop following the logs, whatever the outcome of the test.
+        log_handles.iter().for_each(JoinHandle::abort);
+
+        let states = service_states(&services).await;
+        let oom_killed = states.iter().any(|(_, state)| state.oom_killed);
+        let mut result = match res {
+            Ok(_) if !oom_killed => TestResult::pass(self.test_id(test), duration, Some(logs)),
+            res => {
+                let mut msg = res.err().unwrap_or_default();
+                // a service dying of OOM fails the test, even if the test body succeeded.
+                for (service, state) in &states {
+                    let note = if state.oom_killed {
+                        format!("service `{service}` was OOM-killed")
+                    } else if let (false, Some(code)) = (state.running, state.exit_code) {
+                        format!("service `{service}` exited with code {code}")
+                    } else {
+                        continue;
+                    };
+                    if !msg.is_empty() {
+                        msg.push('\n');
+                    }
+                    msg.push_str(&note);
+                }
+                TestResult::fail(self.test_id(test), msg, duration, Some(logs))
+            }
+        };
+        // the checkpoints of a shared app are reported with the test that took them
+        result.checkpoints = std::mem::take(&mut *checkpoints.lock().unwrap());
+
+        Ok(result)
+    }
+
+    /// Resets a shared app for the next test. Returns `None` if the app can't be reused, and must
+    /// be instantiated again.
+    async fn reset_app(&self, app: App) -> Option<App> {
+        let reset = self.app.reset.as_ref()?;
+        match (reset.0)(app.clone()).await {
+            Ok(()) => Some(app),
+            Err(e) => {
+                eprintln!(
+                    "error resetting app `{}`, instantiating it again: {e:#}",
+                    self.app.name
+                );
+                None
+            }
         }
+    }
 
-        Ok(success)
+    /// Report a test result to the emitter and to all the reporters, and record it in the run
+    /// report.
+    fn report(
+        &self,
+        octopod: &Octopod,
+        state: &RunState,
+        emitter: &mut Emitter,
+        result: TestResult,
+        show_logs: bool,
+    ) -> anyhow::Result<()> {
+        for reporter in &octopod.reporters {
+            reporter.test_result(&self.app.name, &result);
+        }
+        state.records.lock().unwrap().push(TestRecord {
+            app: result.app.clone(),
+            name: result.name.clone(),
+            display_name: result.display_name.clone(),
+            outcome: result.outcome.clone(),
+            duration: result.duration,
+        });
+        if let Some(ref dir) = octopod.log_archive {
+            if let Err(e) = report::archive_test(dir, &result) {
+                eprintln!("error archiving the logs of `{}`: {e:#}", result.name);
+            }
+        }
+        emitter.emit(result, show_logs)?;
+
+        Ok(())
+    }
+}
+
+/// Groups the tests declared by `decls` in a suite per app, keeping only the tests whose name, or
+/// display name, contains one of `filters`, if any. Apps left without tests are skipped.
+fn collect_suites<'a>(
+    apps: Vec<AppConfig>,
+    decls: impl IntoIterator<Item = &'a TestDecl>,
+    filters: &[String],
+) -> Result<Vec<TestSuite>, OctopodError> {
+    let mut suites: HashMap<String, TestSuite> = HashMap::new();
+    for config in apps {
+        let name = config.name.clone();
+        if suites.contains_key(&name) {
+            return Err(OctopodError::config(format!(
+                "app `{name}` is registered more than once"
+            )));
+        }
+        suites.insert(name, TestSuite::new(config));
+    }
+
+    for decl in decls {
+        let suite = suites
+            .get_mut(decl.app)
+            .with_context(|| format!("unknown app `{}` in test `{}`", decl.app, decl.name))
+            .map_err(OctopodError::Config)?;
+        let matches = |filter: &String| {
+            decl.name.contains(filter.as_str())
+                || decl
+                    .display_name
+                    .is_some_and(|name| name.contains(filter.as_str()))
+        };
+        if !filters.is_empty() && !filters.iter().any(matches) {
+            continue;
+        }
+
+        let test = Test {
+            f: decl.f,
+            name: decl.name.into(),
+            display_name: decl.display_name,
+            ignore: decl.ignore,
+            show_logs: decl.show_logs,
+            ignore_if: decl.ignore_if,
+            env: decl.env,
+        };
+        suite.tests.push(test);
+    }
+
+    Ok(suites
+        .into_values()
+        .filter(|suite| filters.is_empty() || !suite.tests.is_empty())
+        .collect())
+}
+
+/// Runs `fut` to completion, unless `deadline` passes first, in which case `None` is returned.
+async fn within<F: Future>(deadline: Option<Instant>, fut: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
+
+/// Extracts the panic message from a failed test task.
+fn panic_message(e: JoinError) -> String {
+    match e.try_into_panic() {
+        Ok(panic) => {
+            if let Some(e) = panic.downcast_ref::<&str>() {
+                e.to_string()
+            } else if let Ok(e) = panic.downcast::<String>() {
+                *e
+            } else {
+                "task panicked with no message".into()
+            }
+        }
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Keeps the tests of shard `index` out of `total`. Without timings, the tests are assigned to the
+/// shards in turn, in the order of their names. With timings, the tests are assigned from the
+/// slowest, each to the shard with the least expected duration so far.
+fn shard(
+    mut suites: Vec<TestSuite>,
+    index: usize,
+    total: usize,
+    timings: Option<&Timings>,
+) -> Result<Vec<TestSuite>, OctopodError> {
+    if index >= total {
+        return Err(OctopodError::config(format!(
+            "invalid shard {index} of {total}, the index must be less than the number of shards"
+        )));
+    }
+    let mut names: Vec<_> = suites
+        .iter()
+        .flat_map(|suite| &suite.tests)
+        .map(|test| test.name.clone())
+        .collect();
+    names.sort();
+    let keep: HashSet<_> = match timings {
+        Some(timings) => {
+            // the sort is stable, so tests with the same duration stay ordered by name
+            names.sort_by_key(|name| std::cmp::Reverse(timings.estimate(name)));
+            let mut loads = vec![Duration::ZERO; total];
+            let mut keep = HashSet::new();
+            for name in names {
+                let (shard, _) = loads
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, load)| **load)
+                    .expect("there is at least one shard");
+                loads[shard] += timings.estimate(&name);
+                if shard == index {
+                    keep.insert(name);
+                }
+            }
+            keep
+        }
+        None => names
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| i % total == index)
+            .map(|(_, name)| name)
+            .collect(),
+    };
+    for suite in &mut suites {
+        suite.tests.retain(|test| keep.contains(&test.name));
+    }
+    suites.retain(|suite| !suite.tests.is_empty());
+
+    Ok(suites)
+}
+
+/// Orders the tests of each suite, and the suites, by decreasing expected duration, so that the
+/// slowest tests don't delay the end of the run.
+fn sort_slowest_first(suites: &mut [TestSuite], timings: &Timings) {
+    for suite in suites.iter_mut() {
+        suite
+            .tests
+            .sort_by_key(|test| std::cmp::Reverse(timings.estimate(&test.name)));
+    }
+    suites.sort_by_key(|suite| {
+        std::cmp::Reverse(
+            suite
+                .tests
+                .iter()
+                .map(|test| timings.estimate(&test.name))
+                .sum::<Duration>(),
+        )
+    });
+}
+
+/// Merges the results of the iterations of a test. A test that passed some iterations, and failed
+/// others, is flaky.
+fn merge_iterations(mut results: Vec<TestResult>) -> TestResult {
+    let total = results.len();
+    let duration = results.iter().map(|r| r.duration).sum();
+    let failures = results
+        .iter()
+        .filter(|r| matches!(r.outcome, TestOutcome::Fail { .. }))
+        .count();
+    // report the first failure, if any.
+    let index = results
+        .iter()
+        .position(|r| matches!(r.outcome, TestOutcome::Fail { .. }))
+        .unwrap_or(total - 1);
+    let mut result = results.swap_remove(index);
+    result.duration = duration;
+    if failures > 0 && failures < total {
+        if let TestOutcome::Fail { ref output } = result.outcome {
+            let output = format!("failed {failures} of {total} iterations\n{output}");
+            result.outcome = TestOutcome::Flaky { output };
+        }
+    }
+
+    result
+}
+
+/// Builds a valid podman resource name out of `parts`.
+fn resource_name(parts: &[&str]) -> String {
+    parts
+        .join("-")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Returns the state of each of the services, to diagnose failures.
+async fn service_states(services: &[Service]) -> Vec<(String, ServiceState)> {
+    let mut states = Vec::new();
+    for service in services {
+        match service.state().await {
+            Ok(state) => states.push((service.name.clone(), state)),
+            Err(e) => eprintln!("error inspecting service `{}`: {e}", service.name),
+        }
     }
+
+    states
 }
 
 #[derive(Clone, Debug)]
 struct Network {
     name: String,
+    run_id: String,
+    dns_servers: Vec<IpAddr>,
+    dns_search: Vec<String>,
 }
 
 impl Network {
@@ -188,8 +1395,21 @@ impl Network {
     }
 }
 
+#[derive(Clone)]
 pub struct App {
     services: HashMap<String, Service>,
+    /// Configuration of the services the app was instantiated with.
+    configs: Vec<ServiceConfig>,
+    network: Network,
+    driver: Driver,
+    resources: Resources,
+    /// Set to true when the test is cancelled.
+    cancelled: watch::Receiver<bool>,
+    tmp_dir: PathBuf,
+    /// Logs captured with `checkpoint_logs`.
+    checkpoints: Arc<Mutex<Vec<Checkpoint>>>,
+    /// When the last checkpoint was taken, if any.
+    checkpointed_at: Arc<Mutex<Option<SystemTime>>>,
 }
 
 impl App {
@@ -197,13 +1417,216 @@ impl App {
         self.services.get(service)
     }
 
-    fn logs(&self, driver: &Driver) -> impl Stream<Item = LogLine> {
+    /// Like `service`, but returns a descriptive error if the service doesn't exist in the app.
+    pub fn service_expect(&self, service: &str) -> anyhow::Result<&Service> {
+        self.services.get(service).with_context(|| {
+            let mut names: Vec<_> = self.services.keys().map(|n| format!("`{n}`")).collect();
+            names.sort();
+            format!(
+                "no service `{service}` in app, available services are: {}",
+                names.join(", ")
+            )
+        })
+    }
+
+    /// Iterate over all the services of the app, along with their names.
+    pub fn services(&self) -> impl Iterator<Item = (&str, &Service)> {
+        self.services
+            .iter()
+            .map(|(name, service)| (name.as_str(), service))
+    }
+
+    /// Wait for all the services with a health check to pass it, checking the services
+    /// concurrently. Returns an error listing the services that were still unhealthy once
+    /// `timeout` expired. Useful to wait for the app to recover after disrupting its services.
+    pub async fn wait_healthy(&self, timeout: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let checks = self
+            .configs
+            .iter()
+            .filter(|config| config.has_health_check())
+            .filter_map(|config| Some((config, self.services.get(&config.name)?)))
+            .map(|(config, service)| async move {
+                loop {
+                    if self.driver.is_healthy(service, config).await {
+                        return None;
+                    }
+                    if Instant::now() >= deadline {
+                        return Some(format!("`{}`", config.name));
+                    }
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                }
+            });
+        let mut unhealthy: Vec<_> = futures::future::join_all(checks)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        if !unhealthy.is_empty() {
+            unhealthy.sort();
+            bail!(
+                "services still unhealthy after {timeout:?}: {}",
+                unhealthy.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Restart all the services of the app, e.g to simulate a rollout. Services are restarted
+    /// after the services they depend on, and each service is waited for to pass its health
+    /// check, if it has one, before the services depending on it are restarted.
+    pub async fn restart_all(&self) -> anyhow::Result<()> {
+        for config in self.dependency_order()? {
+            let Some(service) = self.services.get(&config.name) else {
+                continue;
+            };
+            self.driver
+                .restart(service)
+                .await
+                .with_context(|| format!("failed to restart service `{}`", config.name))?;
+            if config.has_health_check() {
+                self.driver.wait_healthy(service, config).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Script faults to inject in the services of the app while the test runs, e.g pausing a
+    /// service after 2s, and resuming it after 5s.
+    pub fn chaos(&self) -> Chaos {
+        Chaos::new(self.clone())
+    }
+
+    /// Orders the services so that each service comes after the services it depends on.
+    fn dependency_order(&self) -> anyhow::Result<Vec<&ServiceConfig>> {
+        let mut ordered: Vec<&ServiceConfig> = Vec::new();
+        while ordered.len() < self.configs.len() {
+            let before = ordered.len();
+            for config in &self.configs {
+                if ordered.iter().any(|c| c.name == config.name) {
+                    continue;
+                }
+                let ready = config.depends_on.iter().all(|dep| {
+                    ordered.iter().any(|c| &c.name == dep)
+                        || !self.configs.iter().any(|c| &c.name == dep)
+                });
+                if ready {
+                    ordered.push(config);
+                }
+            }
+            if ordered.len() == before {
+                bail!("cyclic dependencies between the services of the app");
+            }
+        }
+
+        Ok(ordered)
+    }
+
+    /// Create a new service on the app network, while the test is running. The service is cleaned
+    /// up along with the rest of the app.
+    pub async fn spawn_service(&self, config: ServiceConfig) -> anyhow::Result<Service> {
+        self.driver.pull(&config, |_| ()).await?;
+        let service = self
+            .driver
+            .service(&config, &self.network, &self.tmp_dir, &self.resources)
+            .await?;
+        if config.has_health_check() {
+            self.driver.wait_healthy(&service, &config).await?;
+        }
+
+        Ok(service)
+    }
+
+    /// A scratch directory on the host, created for the test, and removed on cleanup, even if the
+    /// test panics. Services can mount it with `ServiceConfig::mount_tmp_dir`.
+    pub fn tmp_dir(&self) -> &Path {
+        &self.tmp_dir
+    }
+
+    /// Register `f` to be run when the app is torn down, even if the test panics, e.g to remove
+    /// external resources created by the test. Cleanup functions run before the services are
+    /// removed, in the reverse order of their registration.
+    pub fn on_cleanup<F, Fut>(&self, f: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let f: resource::CleanupFn = Box::new(move || Box::pin(f()));
+        self.resources.register(Cleanup(Mutex::new(Some(f))));
+    }
+
+    /// Name of the podman network the app's services are connected to.
+    pub fn network_name(&self) -> &str {
+        self.network.name()
+    }
+
+    /// Returns a future that resolves when the test is cancelled, e.g because the run timed out,
+    /// or once the test has completed. After cancellation, the test has a short grace period to
+    /// clean up, e.g stop the tasks it spawned, before it is aborted. The future doesn't borrow
+    /// the app, so it can be moved into spawned tasks.
+    pub fn cancelled(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut cancelled = self.cancelled.clone();
+        async move {
+            while !*cancelled.borrow() {
+                // the sender is dropped once the test has completed
+                if cancelled.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Whether the test was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        *self.cancelled.borrow()
+    }
+
+    /// Escape hatch: returns a client to the podman API octopod is connected to, to perform
+    /// operations that octopod doesn't wrap. Resources created through this client are not
+    /// tracked by octopod, and must be cleaned up by the caller.
+    pub fn podman(&self) -> podman_api::Podman {
+        self.driver.podman()
+    }
+
+    /// Capture the logs produced by the services since the previous checkpoint, under `label`.
+    /// The captured logs are grouped by service, and included in the report of the test, whatever
+    /// its outcome, e.g to see the state of the services before a risky action, even if the test
+    /// later times out.
+    pub async fn checkpoint_logs(&self, label: &str) {
+        let since = self
+            .checkpointed_at
+            .lock()
+            .unwrap()
+            .replace(SystemTime::now());
+        let mut logs = Vec::new();
+        for service in self.services.values() {
+            let (_, stream) = self.driver.logs_since(service, false, since);
+            logs.extend(stream.collect::<Vec<_>>().await);
+        }
+        self.checkpoints
+            .lock()
+            .unwrap()
+            .push((label.to_string(), logs));
+    }
+
+    /// Follow the logs of all the services. The returned handles must be aborted once the logs are
+    /// not needed anymore. If `since_now` is set, only the logs produced from now on are followed.
+    fn logs(
+        &self,
+        driver: &Driver,
+        since_now: bool,
+    ) -> (Vec<JoinHandle<()>>, impl Stream<Item = LogLine>) {
+        let mut handles = Vec::new();
         let mut streams = SelectAll::new();
         for service in self.services.values() {
-            streams.push(driver.logs(service));
+            let (handle, stream) = driver.logs(service, true, since_now);
+            handles.push(handle);
+            streams.push(stream);
         }
 
-        streams
+        (handles, streams)
     }
 }
 
@@ -211,6 +1634,25 @@ impl App {
 pub struct AppConfig {
     name: String,
     services: Vec<ServiceConfig>,
+    dns_servers: Vec<IpAddr>,
+    dns_search: Vec<String>,
+    verify_connectivity: bool,
+    internal_network: bool,
+    dns_enabled: bool,
+    max_concurrency: usize,
+    teardown_order: Vec<String>,
+    init_containers: Vec<ServiceConfig>,
+    reset: Option<ResetFn>,
+}
+
+/// Resets an app between tests.
+#[derive(Clone)]
+struct ResetFn(Arc<dyn Fn(App) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>);
+
+impl fmt::Debug for ResetFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResetFn")
+    }
 }
 
 impl AppConfig {
@@ -218,10 +1660,99 @@ impl AppConfig {
         Self {
             name: name.into(),
             services: Vec::new(),
+            dns_servers: Vec::new(),
+            dns_search: Vec::new(),
+            verify_connectivity: false,
+            internal_network: false,
+            dns_enabled: true,
+            max_concurrency: 1,
+            teardown_order: Vec::new(),
+            init_containers: Vec::new(),
+            reset: None,
         }
     }
 
     pub fn add_service(&mut self, config: ServiceConfig) {
         self.services.push(config);
     }
+
+    /// Set the DNS servers used by the services of the app. Note that podman uses these servers
+    /// in place of the network resolver, so services won't be able to resolve each other by name.
+    pub fn dns_servers(&mut self, servers: impl IntoIterator<Item = IpAddr>) {
+        self.dns_servers.extend(servers);
+    }
+
+    /// Set the DNS search domains used by the services of the app.
+    pub fn dns_search(&mut self, domains: impl IntoIterator<Item = impl Into<String>>) {
+        self.dns_search.extend(domains.into_iter().map(Into::into));
+    }
+
+    /// Once all the services are up, check that each service can resolve the services it
+    /// `depends_on`, before running the test. Requires `sh` in the service images. The check is
+    /// skipped if DNS is disabled on the app network.
+    pub fn verify_connectivity(&mut self) {
+        self.verify_connectivity = true;
+    }
+
+    /// Make the app network internal: services can reach each other, but have no external
+    /// connectivity.
+    pub fn internal_network(&mut self, internal: bool) {
+        self.internal_network = internal;
+    }
+
+    /// Enable or disable DNS on the app network. Without DNS, services can't resolve each other
+    /// by name, and can only be reached by IP. Enabled by default.
+    pub fn dns_enabled(&mut self, enabled: bool) {
+        self.dns_enabled = enabled;
+    }
+
+    /// Run up to `n` tests of the app concurrently, each against its own instance of the app.
+    /// The number of tests running at once across the run is still bounded by
+    /// `Octopod::max_concurrency`. Ignored if the app is reset between tests. Defaults to 1.
+    pub fn max_concurrency(&mut self, n: usize) {
+        self.max_concurrency = n.max(1);
+    }
+
+    /// Add a container that runs to completion, e.g to migrate or seed a database, before the
+    /// services of the app are started. Init containers run in the order they are added, and the
+    /// app fails to instantiate if one of them exits with a non-zero code, or doesn't complete
+    /// within its `start_timeout` (5 minutes by default). They are not part of the services of
+    /// the app.
+    pub fn init_container(&mut self, config: ServiceConfig) {
+        self.init_containers.push(config);
+    }
+
+    /// Instead of instantiating the app for each test, keep it up across the tests of the suite,
+    /// and call `reset` between tests to bring it back to a clean state, e.g truncate tables or
+    /// flush caches. This trades the isolation guarantees of a fresh app for speed: state that
+    /// `reset` doesn't clean up leaks from one test to the next. If `reset` fails, the app is
+    /// instantiated again.
+    pub fn reset_between_tests<F, Fut>(&mut self, reset: F)
+    where
+        F: Fn(App) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.reset = Some(ResetFn(Arc::new(move |app| Box::pin(reset(app)))));
+    }
+
+    /// Stop the `services` first on cleanup, in the given order, e.g to stop an app before its
+    /// database. The other services are stopped afterwards, in the reverse order they were
+    /// started.
+    pub fn teardown_order(&mut self, services: &[&str]) {
+        self.teardown_order = services.iter().map(ToString::to_string).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collect_suites, AppConfig, OctopodError};
+
+    #[test]
+    fn duplicate_app_names_are_rejected() {
+        let apps = vec![AppConfig::new("app"), AppConfig::new("app")];
+        let Err(OctopodError::Config(e)) = collect_suites(apps, std::iter::empty(), &[]) else {
+            panic!("duplicate apps should be a configuration error");
+        };
+        assert_eq!(e.to_string(), "app `app` is registered more than once");
+    }
 }
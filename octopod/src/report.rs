@@ -0,0 +1,258 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    time::Duration,
+};
+
+use maplit::hashmap;
+use serde_json::json;
+
+use anyhow::Context;
+
+use crate::emitter::{TestOutcome, TestResult};
+
+/// Report of a run, returned by `Octopod::run`.
+#[derive(Debug)]
+pub struct RunReport {
+    pub(crate) run_id: String,
+    pub(crate) success: bool,
+    pub(crate) duration: Duration,
+    pub(crate) records: Vec<TestRecord>,
+    pub(crate) host: HostInfo,
+}
+
+impl RunReport {
+    /// Whether all the tests were successful.
+    pub fn success(&self) -> bool {
+        self.success
+    }
+
+    /// The id of the run, used to name and label all the resources created by the run.
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    /// Total duration of the run.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Sum of the durations of all the tests. Compared to the duration of the run, it tells how
+    /// much the tests benefited from running concurrently.
+    pub fn cumulative_duration(&self) -> Duration {
+        self.records.iter().map(|r| r.duration).sum()
+    }
+
+    /// Ratio of the cumulative test time to the duration of the run.
+    pub fn speedup(&self) -> f64 {
+        if self.duration.is_zero() {
+            return 1.0;
+        }
+        self.cumulative_duration().as_secs_f64() / self.duration.as_secs_f64()
+    }
+
+    /// The podman host the run was executed against.
+    pub fn host(&self) -> &HostInfo {
+        &self.host
+    }
+
+    /// The outcome of every test of the run.
+    pub fn tests(&self) -> &[TestRecord] {
+        &self.records
+    }
+
+    pub fn passed_count(&self) -> usize {
+        self.count(|o| matches!(o, TestOutcome::Pass))
+    }
+
+    pub fn failed_count(&self) -> usize {
+        self.count(|o| matches!(o, TestOutcome::Fail { .. }))
+    }
+
+    pub fn ignored_count(&self) -> usize {
+        self.count(|o| matches!(o, TestOutcome::Ignore))
+    }
+
+    /// Number of tests that both passed and failed over repeated iterations.
+    pub fn flaky_count(&self) -> usize {
+        self.count(|o| matches!(o, TestOutcome::Flaky { .. }))
+    }
+
+    fn count(&self, f: impl Fn(&TestOutcome) -> bool) -> usize {
+        self.records.iter().filter(|r| f(&r.outcome)).count()
+    }
+
+    pub(crate) fn write_json(&self, path: &Path, podman_addr: &str) -> anyhow::Result<()> {
+        let tests: Vec<_> = self
+            .records
+            .iter()
+            .map(|record| {
+                json!({
+                    "app": record.app,
+                    "name": record.name,
+                    "display_name": record.display_name,
+                    "outcome": record.outcome.label(),
+                    "message": record.outcome.message(),
+                    "duration": record.duration.as_secs_f64(),
+                })
+            })
+            .collect();
+        let summary = json!({
+            "run_id": self.run_id,
+            "podman_addr": podman_addr,
+            "success": self.success,
+            "total": self.records.len(),
+            "passed": self.passed_count(),
+            "failed": self.failed_count(),
+            "ignored": self.ignored_count(),
+            "flaky": self.flaky_count(),
+            "duration": self.duration.as_secs_f64(),
+            "cumulative_duration": self.cumulative_duration().as_secs_f64(),
+            "host": {
+                "podman_version": self.host.podman_version,
+                "api_version": self.host.api_version,
+                "os": self.host.os,
+                "cgroup_version": self.host.cgroup_version,
+                "rootless": self.host.rootless,
+            },
+            "tests": tests,
+        });
+
+        serde_json::to_writer_pretty(File::create(path)?, &summary)?;
+
+        Ok(())
+    }
+}
+
+/// Writes the logs and the result of a test to `<dir>/<app>/<test>`.
+pub(crate) fn archive_test(dir: &Path, result: &TestResult) -> anyhow::Result<()> {
+    let dir = dir.join(&result.app).join(&result.name);
+    fs::create_dir_all(&dir).with_context(|| format!("failed to create `{}`", dir.display()))?;
+    let mut files: HashMap<&str, File> = HashMap::new();
+    for line in result.logs.iter().flatten() {
+        let file = match files.entry(&line.name) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let path = dir.join(format!("{}.log", line.name));
+                let file = File::create(&path)
+                    .with_context(|| format!("failed to create `{}`", path.display()))?;
+                entry.insert(file)
+            }
+        };
+        writeln!(file, "{}", line.data)?;
+    }
+
+    let summary = json!({
+        "app": result.app,
+        "name": result.name,
+        "display_name": result.display_name,
+        "outcome": result.outcome.label(),
+        "message": result.outcome.message(),
+        "duration": result.duration.as_secs_f64(),
+        "ignore_reason": result.ignore_reason,
+    });
+    serde_json::to_writer_pretty(File::create(dir.join("result.json"))?, &summary)?;
+
+    Ok(())
+}
+
+/// Information about the podman host tests run against, to know the environment failures happened
+/// in.
+#[derive(Clone, Debug)]
+pub struct HostInfo {
+    pub(crate) podman_version: String,
+    pub(crate) api_version: String,
+    pub(crate) os: String,
+    pub(crate) cgroup_version: String,
+    pub(crate) rootless: bool,
+}
+
+impl HostInfo {
+    pub fn podman_version(&self) -> &str {
+        &self.podman_version
+    }
+
+    pub fn api_version(&self) -> &str {
+        &self.api_version
+    }
+
+    /// Operating system of the host, e.g `fedora 37`.
+    pub fn os(&self) -> &str {
+        &self.os
+    }
+
+    /// Cgroup version of the host, `v1` or `v2`.
+    pub fn cgroup_version(&self) -> &str {
+        &self.cgroup_version
+    }
+
+    pub fn rootless(&self) -> bool {
+        self.rootless
+    }
+
+    /// The conditions describing the environment, that tests can be ignored on.
+    pub(crate) fn conditions(&self) -> HashMap<String, bool> {
+        let os = std::env::consts::OS;
+        hashmap! {
+            "rootless".into() => self.rootless,
+            "root".into() => !self.rootless,
+            "cgroupv1".into() => self.cgroup_version == "v1",
+            "cgroupv2".into() => self.cgroup_version == "v2",
+            "linux".into() => os == "linux",
+            "macos".into() => os == "macos",
+            "windows".into() => os == "windows",
+        }
+    }
+}
+
+impl fmt::Display for HostInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "podman {} (API {}) on {}, cgroup {}, {}",
+            self.podman_version,
+            self.api_version,
+            self.os,
+            self.cgroup_version,
+            if self.rootless { "rootless" } else { "root" }
+        )
+    }
+}
+
+/// Outcome of a single test of a run.
+#[derive(Clone, Debug)]
+pub struct TestRecord {
+    pub(crate) app: String,
+    pub(crate) name: String,
+    pub(crate) display_name: String,
+    pub(crate) outcome: TestOutcome,
+    pub(crate) duration: Duration,
+}
+
+impl TestRecord {
+    /// Name of the app the test ran against.
+    pub fn app(&self) -> &str {
+        &self.app
+    }
+
+    /// Fully qualified name of the test.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name the test is reported under, the fully qualified name unless set with `name = "..."`.
+    pub fn display_name(&self) -> &str {
+        &self.display_name
+    }
+
+    pub fn outcome(&self) -> &TestOutcome {
+        &self.outcome
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+}
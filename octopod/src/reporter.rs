@@ -0,0 +1,105 @@
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::emitter::{LogLine, LogStream, TestResult};
+
+/// A reporter receives test events as they happen. Reporters are called in addition to the
+/// default console output.
+#[async_trait::async_trait]
+pub trait Reporter: Send + Sync {
+    /// Called when a test from `app` finishes.
+    fn test_result(&self, app: &str, result: &TestResult);
+
+    /// Called for every log line emitted by a service of `app`, while `test` is running.
+    fn log_line(&self, _app: &str, _test: &str, _line: &LogLine) {}
+
+    /// Called with the progress of the pull of `image`, before the tests run, e.g when a layer is
+    /// downloaded.
+    fn pull_progress(&self, _image: &str, _status: &str) {}
+
+    /// Called once all the suites have run.
+    async fn finish(&self) {}
+}
+
+/// A reporter that streams every event as JSON to a web endpoint, with a POST request per event.
+pub struct WebReporter {
+    sender: Mutex<Option<mpsc::UnboundedSender<Value>>>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl WebReporter {
+    /// Creates a reporter posting events to `url`. Must be called from within a tokio runtime.
+    pub fn new(url: impl Into<String>) -> Self {
+        let url = url.into();
+        let (sender, mut recv) = mpsc::unbounded_channel::<Value>();
+        let handle = tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            while let Some(event) = recv.recv().await {
+                let req = client
+                    .post(&url)
+                    .header("content-type", "application/json")
+                    .body(event.to_string());
+                if let Err(e) = req.send().await {
+                    eprintln!("error reporting to `{url}`: {e}");
+                }
+            }
+        });
+
+        Self {
+            sender: Mutex::new(Some(sender)),
+            handle: Mutex::new(Some(handle)),
+        }
+    }
+
+    fn send(&self, event: Value) {
+        if let Some(ref sender) = *self.sender.lock().unwrap() {
+            let _ = sender.send(event);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Reporter for WebReporter {
+    fn test_result(&self, app: &str, result: &TestResult) {
+        self.send(json!({
+            "type": "result",
+            "app": app,
+            "test": result.display_name,
+            "outcome": result.outcome.label(),
+            "message": result.outcome.message(),
+        }));
+    }
+
+    fn pull_progress(&self, image: &str, status: &str) {
+        self.send(json!({
+            "type": "pull",
+            "image": image,
+            "status": status,
+        }));
+    }
+
+    fn log_line(&self, app: &str, test: &str, line: &LogLine) {
+        self.send(json!({
+            "type": "log",
+            "app": app,
+            "test": test,
+            "service": line.name,
+            "stream": match line.stream {
+                LogStream::Stdout => "stdout",
+                LogStream::Stderr => "stderr",
+            },
+            "data": line.data,
+        }));
+    }
+
+    async fn finish(&self) {
+        // dropping the sender closes the channel, and lets the task drain the pending events.
+        self.sender.lock().unwrap().take();
+        let handle = self.handle.lock().unwrap().take();
+        if let Some(handle) = handle {
+            let _ = handle.await;
+        }
+    }
+}
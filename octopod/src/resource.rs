@@ -1,27 +1,77 @@
-use crate::{driver::Driver, service::Service, Network};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
 
-#[derive(Default)]
+use futures::future::BoxFuture;
+use tempfile::TempDir;
+
+use crate::{
+    driver::{is_not_found, Driver},
+    service::Service,
+    Network,
+};
+
+/// Resources to be freed at the end of a suite. Cloning `Resources` returns a handle to the same
+/// set of resources.
+#[derive(Default, Clone)]
 pub(crate) struct Resources {
-    resources: Vec<Box<dyn Resource>>,
+    resources: Arc<Mutex<Vec<Box<dyn Resource>>>>,
+    /// Names of the services to stop first on cleanup, in order.
+    teardown_order: Arc<Vec<String>>,
 }
 
 impl Resources {
-    pub async fn cleanup(self, driver: &Driver) {
-        for resource in self.resources.into_iter().rev() {
-            if let Err(e) = resource.free(driver).await {
-                eprintln!("error freeing service: {e}");
+    pub fn new(teardown_order: Vec<String>) -> Self {
+        Self {
+            resources: Default::default(),
+            teardown_order: Arc::new(teardown_order),
+        }
+    }
+
+    /// Free the resources in the reverse order of their creation, except for the cleanup functions
+    /// registered by the tests, which run first, and then the services listed in the teardown
+    /// order, in that order.
+    pub async fn cleanup(&self, driver: &Driver) {
+        let mut resources = std::mem::take(&mut *self.resources.lock().unwrap());
+        resources.reverse();
+        // the sort is stable, so unlisted resources keep their reverse creation order
+        resources.sort_by_key(|resource| {
+            let position = resource
+                .service_name()
+                .and_then(|name| self.teardown_order.iter().position(|n| n == name))
+                .unwrap_or(usize::MAX);
+            (!resource.is_cleanup(), position)
+        });
+        for resource in resources {
+            match resource.free(driver).await {
+                // the resource may have been removed along with another one, e.g containers are
+                // removed with their network
+                Err(e) if is_not_found(&e) => (),
+                Err(e) => eprintln!("error freeing resource: {e:#}"),
+                Ok(()) => (),
             }
         }
     }
 
-    pub fn register(&mut self, resource: impl Resource + 'static) {
-        self.resources.push(Box::new(resource));
+    pub fn register(&self, resource: impl Resource + 'static) {
+        self.resources.lock().unwrap().push(Box::new(resource));
     }
 }
 
 #[async_trait::async_trait]
-pub(crate) trait Resource {
+pub(crate) trait Resource: Send + Sync {
     async fn free(&self, driver: &Driver) -> anyhow::Result<()>;
+
+    /// Name of the service, if the resource is a service.
+    fn service_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Whether the resource is a cleanup function registered by a test.
+    fn is_cleanup(&self) -> bool {
+        false
+    }
 }
 
 #[async_trait::async_trait]
@@ -30,6 +80,75 @@ impl Resource for Service {
         driver.destroy_service(self).await?;
         Ok(())
     }
+
+    fn service_name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+}
+
+/// A podman secret.
+#[derive(Clone)]
+pub(crate) struct Secret {
+    pub name: String,
+}
+
+#[async_trait::async_trait]
+impl Resource for Secret {
+    async fn free(&self, driver: &Driver) -> anyhow::Result<()> {
+        driver.destroy_secret(self).await?;
+        Ok(())
+    }
+}
+
+/// A named podman volume.
+#[derive(Clone)]
+pub(crate) struct Volume {
+    pub name: String,
+}
+
+#[async_trait::async_trait]
+impl Resource for Volume {
+    async fn free(&self, driver: &Driver) -> anyhow::Result<()> {
+        driver.destroy_volume(self).await?;
+        Ok(())
+    }
+}
+
+/// A temporary directory on the host.
+pub(crate) struct TmpDir(pub Mutex<Option<TempDir>>);
+
+#[async_trait::async_trait]
+impl Resource for TmpDir {
+    async fn free(&self, _driver: &Driver) -> anyhow::Result<()> {
+        if let Some(dir) = self.0.lock().unwrap().take() {
+            match dir.close() {
+                // the directory was already removed, e.g by the test
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                res => res?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A cleanup function registered by a test, with `App::on_cleanup`.
+pub(crate) struct Cleanup(pub Mutex<Option<CleanupFn>>);
+
+pub(crate) type CleanupFn = Box<dyn FnOnce() -> BoxFuture<'static, anyhow::Result<()>> + Send>;
+
+#[async_trait::async_trait]
+impl Resource for Cleanup {
+    async fn free(&self, _driver: &Driver) -> anyhow::Result<()> {
+        let f = self.0.lock().unwrap().take();
+        if let Some(f) = f {
+            f().await?;
+        }
+        Ok(())
+    }
+
+    fn is_cleanup(&self) -> bool {
+        true
+    }
 }
 
 #[async_trait::async_trait]
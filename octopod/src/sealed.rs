@@ -24,7 +24,11 @@ where
 #[doc(hidden)]
 pub struct TestDecl {
     pub name: &'static str,
+    pub display_name: Option<&'static str>,
     pub app: &'static str,
     pub f: &'static dyn TestFn,
     pub ignore: bool,
+    pub show_logs: bool,
+    pub ignore_if: Option<&'static str>,
+    pub env: &'static [(&'static str, &'static str)],
 }
@@ -1,6 +1,18 @@
-use std::net::IpAddr;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::{driver::Driver, Network};
+use anyhow::Context;
+use podman_api::opts::{ContainerCreateOptsBuilder, ContainerRestartPolicy};
+
+use futures::{Future, Stream};
+
+use crate::{driver::Driver, LogLine, Network};
 
 #[derive(Clone, Debug)]
 pub struct ServiceConfig {
@@ -9,6 +21,85 @@ pub struct ServiceConfig {
     pub(crate) env: Vec<(String, String)>,
     /// Url to health check the service.
     pub(crate) health: Option<(String, u16)>,
+    pub(crate) sysctls: Vec<(String, String)>,
+    /// Resource limits, as (name, soft, hard).
+    pub(crate) ulimits: Vec<(String, u64, u64)>,
+    pub(crate) start_timeout: Option<Duration>,
+    /// Wait for the image's own healthcheck to report the service healthy.
+    pub(crate) image_healthcheck: bool,
+    pub(crate) extra_hosts: Vec<(String, IpAddr)>,
+    /// Secrets, as (id, source file).
+    pub(crate) secrets: Vec<(String, PathBuf)>,
+    /// Names of the services this service depends on.
+    pub(crate) depends_on: Vec<String>,
+    pub(crate) pids_limit: Option<i64>,
+    /// Devices, as (host path, container path).
+    pub(crate) devices: Vec<(String, Option<String>)>,
+    pub(crate) restart_policy: Option<ContainerRestartPolicy>,
+    /// Number of restarts tolerated while waiting for the service to become healthy.
+    pub(crate) max_restarts: u32,
+    pub(crate) pull_policy: Option<PullPolicy>,
+    /// Registry credentials, as (username, password).
+    pub(crate) registry_auth: Option<(String, String)>,
+    /// Environment variable overriding the image, if set.
+    pub(crate) image_env: Option<String>,
+    pub(crate) workdir: Option<String>,
+    pub(crate) host_network: bool,
+    /// Named volumes, as (name, container path).
+    pub(crate) named_volumes: Vec<(String, String)>,
+    /// Commands run in the service once it has started.
+    pub(crate) post_start: Vec<Vec<String>>,
+    pub(crate) read_only_rootfs: bool,
+    /// Platform of the image, as `os/arch[/variant]`.
+    pub(crate) platform: Option<String>,
+    /// Path at which the app temporary directory is mounted.
+    pub(crate) tmp_dir_mount: Option<String>,
+    /// Files mounted read-only in the service, as (contents, container path).
+    pub(crate) config_files: Vec<(String, String)>,
+    /// Command run in the service to check its health.
+    pub(crate) health_exec: Option<Vec<String>>,
+    /// Delay between two health probes.
+    pub(crate) health_interval: Duration,
+    /// Factor the delay between probes is multiplied by after each failed probe, and the maximum
+    /// delay.
+    pub(crate) health_backoff: Option<(f64, Duration)>,
+    /// Fraction of the delay between probes that is randomly added or removed.
+    pub(crate) health_jitter: f64,
+    pub(crate) customizers: Vec<Customizer>,
+    pub(crate) expect_digest: Option<String>,
+    /// Container ports published on the host.
+    pub(crate) published_ports: Vec<(u16, Protocol)>,
+    /// OCI annotations set on the service container.
+    pub(crate) annotations: Vec<(String, String)>,
+    /// Directory the service image is built from, before the run.
+    pub(crate) build_context: Option<PathBuf>,
+    /// Skip the build if the image was already built from the same context.
+    pub(crate) build_cache: bool,
+}
+
+/// A user-provided function applied to the container creation options of a service.
+#[derive(Clone)]
+pub(crate) struct Customizer(
+    pub Arc<dyn Fn(ContainerCreateOptsBuilder) -> ContainerCreateOptsBuilder + Send + Sync>,
+);
+
+impl fmt::Debug for Customizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Customizer")
+    }
+}
+
+/// When to pull the image of a service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PullPolicy {
+    /// Always pull the image.
+    Always,
+    /// Only pull the image if it is not present locally.
+    Missing,
+    /// Pull the image if the registry has a newer version.
+    Newer,
+    /// Never pull the image.
+    Never,
 }
 
 impl ServiceConfig {
@@ -18,10 +109,45 @@ impl ServiceConfig {
             image: image.into(),
             env: Vec::new(),
             health: None,
+            sysctls: Vec::new(),
+            ulimits: Vec::new(),
+            start_timeout: None,
+            image_healthcheck: false,
+            extra_hosts: Vec::new(),
+            secrets: Vec::new(),
+            depends_on: Vec::new(),
+            pids_limit: None,
+            devices: Vec::new(),
+            restart_policy: None,
+            max_restarts: 3,
+            pull_policy: None,
+            registry_auth: None,
+            image_env: None,
+            workdir: None,
+            host_network: false,
+            named_volumes: Vec::new(),
+            post_start: Vec::new(),
+            read_only_rootfs: false,
+            platform: None,
+            tmp_dir_mount: None,
+            config_files: Vec::new(),
+            health_exec: None,
+            health_interval: Duration::from_secs(1),
+            health_backoff: None,
+            health_jitter: 0.0,
+            customizers: Vec::new(),
+            expect_digest: None,
+            published_ports: Vec::new(),
+            annotations: Vec::new(),
+            build_context: None,
+            build_cache: false,
         }
     }
 
-    /// Add environment variables to the service.
+    /// Add environment variables to the service. Values may reference variables with `${VAR}`,
+    /// or `${VAR:-default}`, resolved when the service is created against the variables set
+    /// before on the service, then the host environment. Referencing an undefined variable
+    /// without a default is an error. Use `$$` for a literal `$`.
     pub fn env(
         mut self,
         env: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
@@ -31,13 +157,293 @@ impl ServiceConfig {
         self
     }
 
+    /// Sets the variables of `env`, replacing the values of the variables that are already set.
+    pub(crate) fn override_env(mut self, env: &[(&str, &str)]) -> Self {
+        for (key, value) in env {
+            match self.env.iter_mut().find(|(k, _)| k == key) {
+                Some((_, v)) => *v = value.to_string(),
+                None => self.env.push((key.to_string(), value.to_string())),
+            }
+        }
+        self
+    }
+
     /// Set the URL to be checked for health
     /// If set, the octopod will wait for the health route to return success before proceeding to
-    /// the tests.
+    /// the tests. The check is an HTTP request, so `port` must be a TCP port.
     pub fn health(mut self, uri: impl Into<String>, port: u16) -> Self {
         self.health.replace((uri.into(), port));
         self
     }
+
+    /// Set a namespaced kernel parameter for the service, e.g `net.core.somaxconn`.
+    pub fn sysctl(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.sysctls.push((key.into(), value.into()));
+        self
+    }
+
+    /// Set a resource limit for the service, e.g `nofile`.
+    pub fn ulimit(mut self, name: impl Into<String>, soft: u64, hard: u64) -> Self {
+        self.ulimits.push((name.into(), soft, hard));
+        self
+    }
+
+    /// Fail if the service container hasn't started within `timeout`. This is independent from
+    /// the health check.
+    pub fn start_timeout(mut self, timeout: Duration) -> Self {
+        self.start_timeout.replace(timeout);
+        self
+    }
+
+    /// Check the health of the service by running `cmd` in it, e.g `grpc_health_probe`. The
+    /// service is healthy once the command exits with code 0.
+    pub fn health_exec(mut self, cmd: Vec<String>) -> Self {
+        self.health_exec.replace(cmd);
+        self
+    }
+
+    /// Delay between two health probes of the service. Defaults to 1s.
+    pub fn health_interval(mut self, interval: Duration) -> Self {
+        self.health_interval = interval;
+        self
+    }
+
+    /// Multiply the delay between health probes by `factor` after each failed probe, up to
+    /// `max_interval`, to avoid hammering services that are slow to start.
+    pub fn health_backoff(mut self, factor: f64, max_interval: Duration) -> Self {
+        self.health_backoff.replace((factor, max_interval));
+        self
+    }
+
+    /// Randomly vary the delay between health probes by up to `fraction` of the delay, e.g `0.1`
+    /// for +/-10%.
+    pub fn health_jitter(mut self, fraction: f64) -> Self {
+        self.health_jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Whether the service has a health check to wait for.
+    pub(crate) fn has_health_check(&self) -> bool {
+        self.health.is_some() || self.image_healthcheck || self.health_exec.is_some()
+    }
+
+    /// Wait for the `HEALTHCHECK` defined by the image to report the service healthy before
+    /// proceeding to the tests.
+    pub fn use_image_healthcheck(mut self) -> Self {
+        self.image_healthcheck = true;
+        self
+    }
+
+    /// Add an entry to the service's `/etc/hosts`, resolving `host` to `ip`.
+    pub fn extra_host(mut self, host: impl Into<String>, ip: IpAddr) -> Self {
+        self.extra_hosts.push((host.into(), ip));
+        self
+    }
+
+    /// Pass the content of the `source` file as a secret to the service, mounted at
+    /// `/run/secrets/<id>`. The secret is created in podman when the service is created, and
+    /// removed on cleanup.
+    pub fn secret(mut self, id: impl Into<String>, source: impl Into<PathBuf>) -> Self {
+        self.secrets.push((id.into(), source.into()));
+        self
+    }
+
+    /// Declare that this service depends on the `service` service of the app.
+    pub fn depends_on(mut self, service: impl Into<String>) -> Self {
+        self.depends_on.push(service.into());
+        self
+    }
+
+    /// Limit the number of processes in the service container.
+    pub fn pids_limit(mut self, limit: i64) -> Self {
+        self.pids_limit.replace(limit);
+        self
+    }
+
+    /// Set the restart policy of the service, e.g `ContainerRestartPolicy::OnFailure`.
+    pub fn restart_policy(mut self, policy: ContainerRestartPolicy) -> Self {
+        self.restart_policy.replace(policy);
+        self
+    }
+
+    /// Maximum number of times the service may restart while waiting for it to become healthy,
+    /// before it is considered to be crash-looping. Defaults to 3.
+    pub fn max_restarts(mut self, max: u32) -> Self {
+        self.max_restarts = max;
+        self
+    }
+
+    /// Set when to pull the service image. Overrides `Octopod::default_pull_policy`.
+    pub fn pull_policy(mut self, policy: PullPolicy) -> Self {
+        self.pull_policy.replace(policy);
+        self
+    }
+
+    /// Set the credentials used to pull the service image. Overrides `Octopod::registry_auth`.
+    pub fn registry_auth(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.registry_auth
+            .replace((username.into(), password.into()));
+        self
+    }
+
+    /// Read the image of the service from the `var` environment variable when it is set, falling
+    /// back to the image passed to `new` otherwise. Defaults to `OCTOPOD_IMAGE_<SERVICE>`, where
+    /// `<SERVICE>` is the uppercased name of the service, with non-alphanumeric characters
+    /// replaced by `_`.
+    pub fn image_from_env(mut self, var: impl Into<String>) -> Self {
+        self.image_env.replace(var.into());
+        self
+    }
+
+    /// Build the image of the service from the `context` directory, which must contain a
+    /// `Containerfile` or `Dockerfile`, before the run. The built image is tagged with the image
+    /// of the service, and isn't pulled.
+    pub fn build(mut self, context: impl Into<PathBuf>) -> Self {
+        self.build_context.replace(context.into());
+        self
+    }
+
+    /// Reuse the image built by a previous run if the build context hasn't changed since, instead
+    /// of building it again. The context is compared by hashing its files. Rebuilding can be
+    /// forced with `Octopod::rebuild_images`.
+    pub fn image_from_build_cache(mut self) -> Self {
+        self.build_cache = true;
+        self
+    }
+
+    /// The image the service runs, taking environment overrides into account.
+    pub(crate) fn resolved_image(&self) -> String {
+        let var = self.image_env.clone().unwrap_or_else(|| {
+            let name: String = self
+                .name
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() {
+                        c.to_ascii_uppercase()
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            format!("OCTOPOD_IMAGE_{name}")
+        });
+
+        match std::env::var(var) {
+            Ok(image) if !image.is_empty() => image,
+            _ => self.image.clone(),
+        }
+    }
+
+    /// Set the working directory of the service process, overriding the one set by the image.
+    pub fn workdir(mut self, dir: &str) -> Self {
+        self.workdir.replace(dir.to_string());
+        self
+    }
+
+    /// Run the service on the network of the host, so that it can reach services listening on
+    /// the host's localhost. The service is then not attached to the app network: other services
+    /// can't reach it by name, and it can't reach them by name either. The IP of the service is
+    /// reported as `127.0.0.1`.
+    pub fn host_network(mut self, enabled: bool) -> Self {
+        self.host_network = enabled;
+        self
+    }
+
+    /// Mount the `name` named volume at `container_path`. The volume is created when the app is
+    /// instantiated, and shared by the services of the app that mount the same `name`. It
+    /// survives restarts of the service, and is removed on cleanup.
+    pub fn named_volume(mut self, name: &str, container_path: &str) -> Self {
+        self.named_volumes
+            .push((name.to_string(), container_path.to_string()));
+        self
+    }
+
+    /// Run `cmd` in the service once it has started, and passed its health check if it has one,
+    /// e.g to create a bucket or a database. Commands run in the order they were added, and the
+    /// app fails to instantiate if one of them exits with a non-zero code.
+    pub fn post_start(mut self, cmd: Vec<String>) -> Self {
+        self.post_start.push(cmd);
+        self
+    }
+
+    /// Mount the root filesystem of the service read-only. Combine with `named_volume` to provide
+    /// writable paths.
+    pub fn read_only_rootfs(mut self, enabled: bool) -> Self {
+        self.read_only_rootfs = enabled;
+        self
+    }
+
+    /// Select the platform of a multi-arch image, e.g `linux/amd64` or `linux/arm64/v8`. Defaults to
+    /// the platform of the host.
+    pub fn platform(mut self, platform: &str) -> Self {
+        self.platform.replace(platform.to_string());
+        self
+    }
+
+    /// Split the platform into its os, architecture and variant.
+    pub(crate) fn platform_parts(&self) -> Option<(&str, Option<&str>, Option<&str>)> {
+        let mut parts = self.platform.as_deref()?.splitn(3, '/');
+        Some((parts.next()?, parts.next(), parts.next()))
+    }
+
+    /// Bind mount the temporary directory of the app, `App::tmp_dir`, at `container_path`.
+    pub fn mount_tmp_dir(mut self, container_path: &str) -> Self {
+        self.tmp_dir_mount.replace(container_path.to_string());
+        self
+    }
+
+    /// Mount a read-only file with `contents` at `container_path` in the service, to keep small
+    /// configuration files inline in the tests, rather than in fixture files.
+    pub fn config_file(mut self, contents: &str, container_path: &str) -> Self {
+        self.config_files
+            .push((contents.to_string(), container_path.to_string()));
+        self
+    }
+
+    /// Escape hatch: modify the podman options used to create the service container, for options
+    /// that octopod doesn't wrap. `f` is applied last, so it can override the options set by
+    /// octopod.
+    pub fn customize(
+        mut self,
+        f: impl Fn(ContainerCreateOptsBuilder) -> ContainerCreateOptsBuilder + Send + Sync + 'static,
+    ) -> Self {
+        self.customizers.push(Customizer(Arc::new(f)));
+        self
+    }
+
+    /// Fail to create the service if its image doesn't have the `digest` digest, e.g
+    /// `sha256:...`, to guard against a tag changing under the tests. Images can also be pinned
+    /// by digest directly, with a `repo@sha256:...` reference.
+    pub fn expect_digest(mut self, digest: &str) -> Self {
+        self.expect_digest.replace(digest.to_string());
+        self
+    }
+
+    /// Publish the `container_port` port of the service on a free port of the host, to reach the
+    /// service from the tests without going through the app network. The host port can be
+    /// retrieved with `Service::host_port`.
+    pub fn publish_port(mut self, container_port: u16, protocol: Protocol) -> Self {
+        self.published_ports.push((container_port, protocol));
+        self
+    }
+
+    /// Set the OCI annotation `key` to `value` on the service container.
+    pub fn annotation(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.annotations.push((key.into(), value.into()));
+        self
+    }
+
+    /// Give the service access to the `host_dev` device, e.g `/dev/fuse`, mounted at
+    /// `container_dev`, or at the same path if not provided.
+    pub fn device(mut self, host_dev: &str, container_dev: Option<&str>) -> Self {
+        self.devices
+            .push((host_dev.to_string(), container_dev.map(ToString::to_string)));
+        self
+    }
 }
 
 #[derive(Clone)]
@@ -47,14 +453,126 @@ pub struct Service {
     pub(crate) net: Network,
     pub(crate) id: String,
     pub(crate) driver: Driver,
+    /// Whether the service runs on the host network.
+    pub(crate) host_network: bool,
 }
 
 impl Service {
+    /// The podman id of the service container.
+    pub fn container_id(&self) -> &str {
+        &self.id
+    }
+
     /// Retrieve the IP address of this service.
     pub async fn ip(&self) -> anyhow::Result<IpAddr> {
         self.driver.get_service_ip(self).await
     }
 
+    /// Retrieve the IP address of this service, retrying until an address is available, or
+    /// `timeout` expires. Useful right after `connect`, since podman may not have assigned an
+    /// address yet.
+    pub async fn ip_ready(&self, timeout: Duration) -> anyhow::Result<IpAddr> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.driver.get_service_ip(self).await {
+                Ok(ip) => return Ok(ip),
+                Err(e) if Instant::now() >= deadline => return Err(e),
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+    }
+
+    /// Poll `condition` until it returns `Ok(true)`, e.g to wait for a metric to reach a
+    /// threshold. Fails if the condition isn't met within `timeout`, with the last error returned
+    /// by `condition`, if any.
+    pub async fn wait_until<'a, F, Fut>(
+        &'a self,
+        mut condition: F,
+        timeout: Duration,
+    ) -> anyhow::Result<()>
+    where
+        F: FnMut(&'a Service) -> Fut,
+        Fut: Future<Output = anyhow::Result<bool>> + 'a,
+    {
+        let deadline = Instant::now() + timeout;
+        let mut last_error = None;
+        loop {
+            match condition(self).await {
+                Ok(true) => return Ok(()),
+                Ok(false) => (),
+                Err(e) => last_error = Some(e),
+            }
+            if Instant::now() >= deadline {
+                let msg = format!(
+                    "condition on service `{}` not met after {timeout:?}",
+                    self.name
+                );
+                return Err(match last_error {
+                    Some(e) => e.context(msg),
+                    None => anyhow::anyhow!(msg),
+                });
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Wait for the service to accept TCP connections on `port`, retrying until a connection
+    /// succeeds, or `timeout` expires. On timeout, the last connection error is returned. UDP is
+    /// connectionless, so UDP ports can't be checked this way.
+    pub async fn port_open(&self, port: u16, timeout: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let res = match self.ip().await {
+                Ok(ip) => tokio::net::TcpStream::connect((ip, port))
+                    .await
+                    .map(drop)
+                    .with_context(|| format!("failed to connect to `{}:{port}`", self.name)),
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(()) => return Ok(()),
+                Err(e) if Instant::now() >= deadline => {
+                    return Err(e.context(format!(
+                        "port {port} of service `{}` not open after {timeout:?}",
+                        self.name
+                    )))
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+    }
+
+    /// Follow the logs of the service, e.g to assert on a sequence of log lines. The logs are
+    /// followed until the stream is dropped, or the service is removed.
+    pub fn log_stream(&self) -> impl Stream<Item = LogLine> + Unpin {
+        let (_, stream) = self.driver.logs(self, true, false);
+        stream
+    }
+
+    /// The port mappings of the service: the ports exposed by the container, along with the host
+    /// ports they are published on.
+    pub async fn ports(&self) -> anyhow::Result<Vec<PortMapping>> {
+        self.driver.service_ports(self).await
+    }
+
+    /// The host port the `container_port`/`protocol` port of the service is published on.
+    pub async fn host_port(&self, container_port: u16, protocol: Protocol) -> anyhow::Result<u16> {
+        self.ports()
+            .await?
+            .into_iter()
+            .find(|mapping| {
+                mapping.container_port == container_port && mapping.protocol == protocol
+            })
+            .and_then(|mapping| mapping.host_port)
+            .with_context(|| {
+                format!(
+                    "port {container_port}/{} of service `{}` is not published",
+                    protocol.as_str(),
+                    self.name
+                )
+            })
+    }
+
     /// Disconnect this service from the network.
     pub async fn disconnect(&self) -> anyhow::Result<()> {
         self.driver.disconnect(self).await
@@ -74,4 +592,164 @@ impl Service {
     pub async fn unpause(&self) -> anyhow::Result<()> {
         self.driver.unpause(self).await
     }
+
+    /// Retrieve the environment the service container is running with, including the defaults
+    /// from the image.
+    pub async fn env(&self) -> anyhow::Result<Vec<(String, String)>> {
+        self.driver.service_env(self).await
+    }
+
+    /// Retrieve the OCI annotations of the service container, including the ones set by podman.
+    pub async fn annotations(&self) -> anyhow::Result<HashMap<String, String>> {
+        self.driver.service_annotations(self).await
+    }
+
+    /// Fetch the Prometheus metrics the service exposes on `port`, at `path`, e.g `/metrics`.
+    /// Metrics are keyed by their name and labels, as they appear in the exposition format, e.g
+    /// `http_requests_total{method="get"}`.
+    pub async fn metrics(&self, port: u16, path: &str) -> anyhow::Result<HashMap<String, f64>> {
+        let ip = self.ip().await?;
+        let url = format!("http://{ip}:{port}{path}");
+        let body = reqwest::get(&url)
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .with_context(|| format!("failed to fetch metrics from `{url}`"))?
+            .text()
+            .await?;
+
+        parse_metrics(&body).with_context(|| format!("invalid metrics from `{url}`"))
+    }
+
+    /// Retrieve the current state of the service container.
+    pub async fn state(&self) -> anyhow::Result<ServiceState> {
+        self.driver.service_state(self).await
+    }
+
+    /// Run `cmd` in the service container, and wait for it to complete.
+    pub async fn exec(
+        &self,
+        cmd: impl IntoIterator<Item = impl Into<String>>,
+    ) -> anyhow::Result<ExecOutput> {
+        let cmd: Vec<String> = cmd.into_iter().map(Into::into).collect();
+        self.driver.exec(self, &cmd, None).await
+    }
+
+    /// Like `exec`, but runs `cmd` from the `workdir` directory, instead of the working directory
+    /// of the service.
+    pub async fn exec_in(
+        &self,
+        workdir: &str,
+        cmd: impl IntoIterator<Item = impl Into<String>>,
+    ) -> anyhow::Result<ExecOutput> {
+        let cmd: Vec<String> = cmd.into_iter().map(Into::into).collect();
+        self.driver.exec(self, &cmd, Some(workdir)).await
+    }
+}
+
+/// A port exposed by a service container.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortMapping {
+    pub container_port: u16,
+    /// The host port the container port is published on, if it is published.
+    pub host_port: Option<u16>,
+    pub protocol: Protocol,
+}
+
+/// Transport protocol of a container port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Sctp,
+}
+
+impl Protocol {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Sctp => "sctp",
+        }
+    }
+
+    pub(crate) fn parse(protocol: &str) -> Option<Self> {
+        match protocol {
+            "tcp" => Some(Protocol::Tcp),
+            "udp" => Some(Protocol::Udp),
+            "sctp" => Some(Protocol::Sctp),
+            _ => None,
+        }
+    }
+}
+
+/// Output of a command run in a service container.
+#[derive(Clone, Debug)]
+pub struct ExecOutput {
+    pub exit_code: i64,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl ExecOutput {
+    /// Whether the command exited successfully.
+    pub fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+}
+
+/// State of a service container, as reported by podman.
+#[derive(Clone, Debug)]
+pub struct ServiceState {
+    /// Container status, e.g `running` or `exited`.
+    pub status: String,
+    pub running: bool,
+    /// Exit code of the container, if it has exited.
+    pub exit_code: Option<i32>,
+    /// Number of times the container was restarted by podman.
+    pub restart_count: u32,
+    /// Whether the container was killed by the OOM killer.
+    pub oom_killed: bool,
+    /// Status of the image healthcheck, if the image defines one.
+    pub health: Option<String>,
+}
+
+/// Parses metrics in the Prometheus text exposition format.
+fn parse_metrics(text: &str) -> anyhow::Result<HashMap<String, f64>> {
+    let mut metrics = HashMap::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // label values may contain spaces and braces, so the end of the labels is found by
+        // skipping over quoted strings
+        let mut end = line.find(char::is_whitespace).unwrap_or(line.len());
+        if let Some(start) = line[..end].find('{') {
+            let mut quoted = false;
+            let mut escaped = false;
+            end = line[start..]
+                .char_indices()
+                .find_map(|(i, c)| {
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' if quoted => escaped = true,
+                        '"' => quoted = !quoted,
+                        '}' if !quoted => return Some(start + i + 1),
+                        _ => (),
+                    }
+                    None
+                })
+                .with_context(|| format!("unterminated labels in `{line}`"))?;
+        }
+        let (series, rest) = line.split_at(end);
+        let value = rest
+            .split_whitespace()
+            .next()
+            .with_context(|| format!("missing value in `{line}`"))?;
+        let value = value
+            .parse()
+            .with_context(|| format!("invalid value in `{line}`"))?;
+        metrics.insert(series.to_string(), value);
+    }
+
+    Ok(metrics)
 }
@@ -0,0 +1,69 @@
+use std::{collections::HashMap, fs::File, io, path::Path, time::Duration};
+
+use anyhow::{bail, Context};
+
+use crate::{emitter::TestOutcome, report::TestRecord};
+
+/// Durations of the tests in previous runs, by fully qualified test name, persisted as a JSON
+/// object of durations in seconds.
+#[derive(Default)]
+pub(crate) struct Timings {
+    durations: HashMap<String, f64>,
+    /// Median of the durations, expected of the tests without timings.
+    median: f64,
+}
+
+impl Timings {
+    /// Reads the timings from `path`. A missing file is treated as empty, e.g on the first run.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let durations: HashMap<String, f64> =
+            serde_json::from_reader(file).context("invalid timings file")?;
+        for (name, secs) in &durations {
+            if !secs.is_finite() || *secs < 0.0 {
+                bail!("invalid duration {secs} for test `{name}` in timings file");
+            }
+        }
+        let median = median(&durations);
+
+        Ok(Self { durations, median })
+    }
+
+    /// Expected duration of the test `name`. Tests without timings are expected to take the
+    /// median duration of the known tests.
+    pub fn estimate(&self, name: &str) -> Duration {
+        let secs = self.durations.get(name).copied().unwrap_or(self.median);
+        Duration::from_secs_f64(secs)
+    }
+
+    /// Updates the timings with the durations of the tests that ran.
+    pub fn record(&mut self, records: &[TestRecord]) {
+        for record in records {
+            // ignored tests and tests that were not run have no meaningful duration
+            if matches!(record.outcome, TestOutcome::Ignore) || record.duration.is_zero() {
+                continue;
+            }
+            self.durations
+                .insert(record.name.clone(), record.duration.as_secs_f64());
+        }
+        self.median = median(&self.durations);
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        serde_json::to_writer_pretty(File::create(path)?, &self.durations)?;
+        Ok(())
+    }
+}
+
+fn median(durations: &HashMap<String, f64>) -> f64 {
+    let mut durations: Vec<_> = durations.values().copied().collect();
+    if durations.is_empty() {
+        return 0.0;
+    }
+    durations.sort_by(f64::total_cmp);
+    durations[durations.len() / 2]
+}